@@ -0,0 +1,40 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use std::path::Path;
+use unsb3::VM;
+
+/// Loads the project in `benches/fixtures/{name}` with its output discarded.
+fn load(name: &str) -> VM {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("benches/fixtures")
+        .join(name);
+    let mut vm = VM::from_project_dir(&dir).expect("invalid fixture");
+    vm.set_output(Box::new(std::io::sink()));
+    vm
+}
+
+/// A loop that does a few arithmetic operations on variables per iteration,
+/// which mostly measures how fast reporters are evaluated.
+fn arithmetic(c: &mut Criterion) {
+    c.bench_function("arithmetic", |b| {
+        b.iter_batched(
+            || load("arithmetic"),
+            |vm| vm.run().expect("running failed"),
+            BatchSize::SmallInput,
+        );
+    });
+}
+
+/// Prints 100000 characters one at a time, which mostly measures the cost of
+/// writing output.
+fn print(c: &mut Criterion) {
+    c.bench_function("print", |b| {
+        b.iter_batched(
+            || load("print"),
+            |vm| vm.run().expect("running failed"),
+            BatchSize::SmallInput,
+        );
+    });
+}
+
+criterion_group!(benches, arithmetic, print);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+use std::{
+    cell::Cell,
+    fmt, thread,
+    time::{Duration, Instant},
+};
+
+/// The VM's source of time. Replacing it lets the VM run where the standard
+/// library's clock and sleeping are unavailable, such as on WebAssembly.
+pub trait Clock: fmt::Debug {
+    /// The number of seconds since some fixed point in the past.
+    fn now(&self) -> f64;
+
+    /// Blocks until `duration` has passed.
+    fn sleep(&self, duration: Duration);
+}
+
+/// Uses the operating system's clock and actually sleeps.
+#[derive(Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        // Sleeping tends to overshoot by up to a millisecond or more, so the
+        // final stretch is spent spinning instead, which keeps very short
+        // waits accurate.
+        const SPIN_TIME: Duration = Duration::from_millis(1);
+
+        let start = Instant::now();
+        if let Some(sleep_time) = duration.checked_sub(SPIN_TIME) {
+            thread::sleep(sleep_time);
+        }
+        while start.elapsed() < duration {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+/// A clock that only moves forward when slept on. Sleeping returns
+/// immediately, so waits resolve instantly while the timer still reports the
+/// time that would have passed.
+#[derive(Debug, Default)]
+pub struct VirtualClock {
+    now: Cell<f64>,
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> f64 {
+        self.now.get()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiny_waits_do_not_drift() {
+        const WAIT: Duration = Duration::from_micros(1);
+        const WAITS: u32 = 1000;
+
+        let clock = SystemClock::default();
+        let start = Instant::now();
+        for _ in 0..WAITS {
+            clock.sleep(WAIT);
+        }
+        let drift = start.elapsed().saturating_sub(WAIT * WAITS);
+        // Sleeping for each wait would add at least 50 microseconds apiece.
+        assert!(drift < Duration::from_millis(50), "drifted by {drift:?}");
+    }
+}
@@ -2,12 +2,26 @@ use crate::{
     expr::Expr,
     proc::{Custom, Procs},
     statement::Statement,
+    vm::is_builtin_statement,
 };
 use ecow::EcoString;
+use log::warn;
 use sb3_stuff::Value;
-use serde::Deserialize;
+use serde::{
+    de::{
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        IgnoredAny, MapAccess, SeqAccess, Visitor,
+    },
+    Deserialize, Deserializer,
+};
 use serde_json::Value as Json;
-use std::{borrow::Cow, collections::HashMap, fmt::Display};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::{self, Display},
+    marker::PhantomData,
+    rc::Rc,
+};
 use thiserror::Error;
 
 pub struct DeCtx<'a> {
@@ -24,6 +38,22 @@ pub enum DeError {
     MissingInput(String),
     #[error("missing mutation for block that requires it")]
     MissingMutation,
+    #[error("unsupported opcode: `{0}`")]
+    UnsupportedOpcode(String),
+    #[error("statement block `{0}` was used as a reporter")]
+    StatementAsReporter(String),
+    #[error("unsupported input: {0}")]
+    UnsupportedInput(String),
+    #[error("invalid field `{0}`")]
+    InvalidField(String),
+    #[error("missing `{0}` in mutation")]
+    MissingMutationField(String),
+    #[error("invalid `{0}` in mutation")]
+    InvalidMutationField(String),
+    #[error("unsupported stop option `{0}`")]
+    UnsupportedStopOption(String),
+    #[error("found more than one target named `{0}`")]
+    DuplicateTarget(String),
 }
 
 type DeResult<T> = Result<T, DeError>;
@@ -41,6 +71,11 @@ pub struct Block<'a> {
     // pub parent: Option<String>,
     #[serde(borrow)]
     pub next: Option<Cow<'a, str>>,
+    /// Whether the block starts a script rather than being inside another
+    /// block.
+    #[serde(rename = "topLevel")]
+    #[serde(default)]
+    pub top_level: bool,
     #[serde(default)]
     #[serde(borrow)]
     pub inputs: HashMap<Cow<'a, str>, Json>,
@@ -50,23 +85,98 @@ pub struct Block<'a> {
     pub mutation: Option<Mutation<'a>>,
 }
 
+/// An entry in a target's `blocks` map. Variable and list reporters that
+/// aren't inside another block are stored in a compressed array form, which
+/// is skipped since no script can refer to them.
+#[derive(Debug)]
+pub enum BlockEntry<'a> {
+    Compressed,
+    Block(Box<Block<'a>>),
+}
+
+/// Arrays and objects are told apart up front rather than with
+/// `#[serde(untagged)]`, which would replace any error in a block with one
+/// that doesn't say what went wrong.
+impl<'de: 'a, 'a> Deserialize<'de> for BlockEntry<'a> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct EntryVisitor<'a>(PhantomData<BlockEntry<'a>>);
+
+        impl<'de: 'a, 'a> Visitor<'de> for EntryVisitor<'a> {
+            type Value = BlockEntry<'a>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a block or a compressed reporter")
+            }
+
+            fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                IgnoredAny::deserialize(SeqAccessDeserializer::new(seq))
+                    .map(|IgnoredAny| BlockEntry::Compressed)
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                Block::deserialize(MapAccessDeserializer::new(map))
+                    .map(|block| BlockEntry::Block(Box::new(block)))
+            }
+        }
+
+        deserializer.deserialize_any(EntryVisitor(PhantomData))
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Mutation<'a> {
     #[serde(borrow)]
     proccode: Option<Cow<'a, str>>,
     argumentids: Option<String>,
     argumentnames: Option<String>,
+    warp: Option<Json>,
+}
+
+impl Mutation<'_> {
+    fn proccode(&self) -> DeResult<&str> {
+        self.proccode
+            .as_deref()
+            .ok_or_else(|| DeError::MissingMutationField("proccode".to_owned()))
+    }
+}
+
+/// Parses a list that a mutation stores as a JSON string, such as the IDs of
+/// the arguments of a custom block.
+fn mutation_list(json: Option<&str>, name: &str) -> DeResult<Vec<EcoString>> {
+    let json =
+        json.ok_or_else(|| DeError::MissingMutationField(name.to_owned()))?;
+    serde_json::from_str(json)
+        .map_err(|_| DeError::InvalidMutationField(name.to_owned()))
 }
 
 impl<'a> DeCtx<'a> {
-    pub const fn new(blocks: HashMap<EcoString, Block<'a>>) -> Self {
+    pub fn new(blocks: HashMap<EcoString, BlockEntry<'a>>) -> Self {
+        let blocks = blocks
+            .into_iter()
+            .filter_map(|(id, entry)| match entry {
+                BlockEntry::Block(block) => Some((id, *block)),
+                BlockEntry::Compressed => None,
+            })
+            .collect();
         Self { blocks }
     }
 
     pub fn build_procs(&self) -> DeResult<Procs> {
         let mut when_flag_clicked = Vec::new();
+        let mut start_as_clone = Vec::new();
         let mut custom = HashMap::new();
         let mut broadcasts = HashMap::new();
+        let mut when_key_pressed = HashMap::new();
+        let mut when_clicked = Vec::new();
 
         for block in self.blocks.values() {
             match &*block.opcode {
@@ -78,40 +188,38 @@ impl<'a> DeCtx<'a> {
                             .get("custom_block")
                             .and_then(get_rep)
                             .and_then(Json::as_str)
-                            .expect("missing prototype for custom block");
+                            .ok_or_else(|| {
+                                DeError::MissingInput("custom_block".to_owned())
+                            })?;
                         let proto = self.get(proto_id)?;
                         let mutation = proto
                             .mutation
                             .as_ref()
                             .ok_or(DeError::MissingMutation)?;
-                        let name = mutation
-                            .proccode
-                            .as_ref()
-                            .expect("missing proccode for custom block")
-                            .to_string();
-                        let arg_ids: Vec<EcoString> = serde_json::from_str(
-                            mutation
-                                .argumentids
-                                .as_deref()
-                                .expect("missing argumentids"),
-                        )
-                        .expect("argumentids was not valid JSON");
-                        let arg_names: Vec<EcoString> = serde_json::from_str(
-                            mutation
-                                .argumentnames
-                                .as_ref()
-                                .expect("missing argumentnames"),
-                        )
-                        .expect("argumentnames was not valid JSON");
-                        let arg_names_by_id = arg_ids
-                            .into_iter()
-                            .zip(arg_names.into_iter())
-                            .collect();
+                        let name = mutation.proccode()?.to_owned();
+                        let arg_ids = mutation_list(
+                            mutation.argumentids.as_deref(),
+                            "argumentids",
+                        )?;
+                        let arg_names = mutation_list(
+                            mutation.argumentnames.as_deref(),
+                            "argumentnames",
+                        )?;
+                        // Scratch stores this as a string, but other tools
+                        // may write a boolean.
+                        let warp = match &mutation.warp {
+                            Some(Json::Bool(warp)) => *warp,
+                            Some(Json::String(warp)) => warp == "true",
+                            _ => false,
+                        };
+                        let arg_names_by_id =
+                            arg_ids.into_iter().zip(arg_names).collect();
                         custom.insert(
                             name,
                             Custom {
                                 arg_names_by_id,
-                                body,
+                                body: Rc::new(body),
+                                warp,
                             },
                         );
                     }
@@ -119,7 +227,13 @@ impl<'a> DeCtx<'a> {
                 "event_whenflagclicked" => {
                     if let Some(next) = block.next.as_ref() {
                         let body = self.build_statement(next)?;
-                        when_flag_clicked.push(body);
+                        when_flag_clicked.push(Rc::new(body));
+                    }
+                }
+                "control_start_as_clone" => {
+                    if let Some(next) = block.next.as_ref() {
+                        let body = self.build_statement(next)?;
+                        start_as_clone.push(Rc::new(body));
                     }
                 }
                 "event_whenbroadcastreceived" => {
@@ -130,17 +244,42 @@ impl<'a> DeCtx<'a> {
                         broadcasts
                             .entry(broadcast_name)
                             .or_insert_with(|| Vec::with_capacity(1))
-                            .push(body);
+                            .push(Rc::new(body));
                     }
                 }
+                "event_whenkeypressed" => {
+                    if let Some(next) = block.next.as_ref() {
+                        let key = str_field(block, "KEY_OPTION")?.to_owned();
+                        let body = self.build_statement(next)?;
+                        when_key_pressed
+                            .entry(key)
+                            .or_insert_with(|| Vec::with_capacity(1))
+                            .push(Rc::new(body));
+                    }
+                }
+                "event_whenthisspriteclicked" | "event_whenstageclicked" => {
+                    if let Some(next) = block.next.as_ref() {
+                        let body = self.build_statement(next)?;
+                        when_clicked.push(Rc::new(body));
+                    }
+                }
+                opcode if block.top_level && block.next.is_some() => {
+                    warn!(
+                        "ignoring a script that starts with `{opcode}`, which \
+                         isn't a supported hat block"
+                    );
+                }
                 _ => {}
             }
         }
 
         Ok(Procs {
             when_flag_clicked,
+            start_as_clone,
             custom,
             broadcasts,
+            when_key_pressed,
+            when_clicked,
         })
     }
 
@@ -159,7 +298,7 @@ impl<'a> DeCtx<'a> {
                 }
             }
 
-            Ok(Statement::Do(blocks))
+            Ok(Statement::Do(blocks.into()))
         } else {
             self.build_single_statement(block)
         }
@@ -172,7 +311,7 @@ impl<'a> DeCtx<'a> {
                 let if_true = self.substack(block, "SUBSTACK")?;
                 Ok(Statement::If {
                     condition,
-                    if_true: Box::new(if_true),
+                    if_true: Rc::new(if_true),
                 })
             }
             "control_if_else" => {
@@ -181,35 +320,35 @@ impl<'a> DeCtx<'a> {
                 let if_false = self.substack(block, "SUBSTACK2")?;
                 Ok(Statement::IfElse {
                     condition,
-                    if_true: Box::new(if_true),
-                    if_false: Box::new(if_false),
+                    if_true: Rc::new(if_true),
+                    if_false: Rc::new(if_false),
                 })
             }
             "control_repeat" => {
                 let times = self.input(block, "TIMES")?;
-                let body = Box::new(self.substack(block, "SUBSTACK")?);
+                let body = Rc::new(self.substack(block, "SUBSTACK")?);
                 Ok(Statement::Repeat { times, body })
             }
             "control_forever" => {
                 let body = self.substack(block, "SUBSTACK")?;
                 Ok(Statement::Forever {
-                    body: Box::new(body),
+                    body: Rc::new(body),
                 })
             }
             "control_repeat_until" => {
                 let condition = self.input(block, "CONDITION")?;
-                let body = Box::new(self.substack(block, "SUBSTACK")?);
+                let body = Rc::new(self.substack(block, "SUBSTACK")?);
                 Ok(Statement::Until { condition, body })
             }
             "control_while" => {
                 let condition = self.input(block, "CONDITION")?;
-                let body = Box::new(self.substack(block, "SUBSTACK")?);
+                let body = Rc::new(self.substack(block, "SUBSTACK")?);
                 Ok(Statement::While { condition, body })
             }
             "control_for_each" => {
                 let counter_id = var_list_field(block, "VARIABLE")?.into();
                 let times = self.input(block, "VALUE")?;
-                let body = Box::new(self.substack(block, "SUBSTACK")?);
+                let body = Rc::new(self.substack(block, "SUBSTACK")?);
                 Ok(Statement::For {
                     counter_id,
                     times,
@@ -219,11 +358,7 @@ impl<'a> DeCtx<'a> {
             "procedures_call" => {
                 let mutation =
                     block.mutation.as_ref().ok_or(DeError::MissingMutation)?;
-                let proccode = mutation
-                    .proccode
-                    .as_ref()
-                    .expect("missing proccode for custom block")
-                    .to_string();
+                let proccode = mutation.proccode()?.to_owned();
                 let args = block
                     .inputs
                     .iter()
@@ -245,6 +380,16 @@ impl<'a> DeCtx<'a> {
                 let item = self.input(block, "ITEM")?;
                 Ok(Statement::AddToList { list_id, item })
             }
+            "data_insertatlist" => {
+                let list_id = var_list_field(block, "LIST")?.into();
+                let index = self.input(block, "INDEX")?;
+                let item = self.input(block, "ITEM")?;
+                Ok(Statement::InsertAtList {
+                    list_id,
+                    index,
+                    item,
+                })
+            }
             "data_replaceitemoflist" => {
                 let list_id = var_list_field(block, "LIST")?.into();
                 let index = self.input(block, "INDEX")?;
@@ -270,19 +415,15 @@ impl<'a> DeCtx<'a> {
                 match stop_option {
                     "all" => Ok(Statement::StopAll),
                     "this script" => Ok(Statement::StopThisScript),
-                    _ => {
-                        dbg!(stop_option);
-                        todo!()
+                    "other scripts in sprite" | "other scripts in stage" => {
+                        Ok(Statement::StopOtherScripts)
                     }
+                    _ => Err(DeError::UnsupportedStopOption(
+                        stop_option.to_owned(),
+                    )),
                 }
             }
             opcode => {
-                // Field generation has to be done manually for each opcode that uses it
-                if !block.fields.is_empty() {
-                    dbg!(block);
-                    todo!();
-                }
-
                 let inputs = block
                     .inputs
                     .iter()
@@ -291,56 +432,54 @@ impl<'a> DeCtx<'a> {
                 Ok(Statement::Regular {
                     opcode: opcode.into(),
                     inputs,
+                    fields: fields(block)?,
                 })
             }
         }
     }
 
     fn build_expr(&self, json: &Json) -> DeResult<Expr> {
-        let rep = get_rep(json).expect("invalid reporter");
-        match rep {
+        let unsupported = || DeError::UnsupportedInput(json.to_string());
+        match get_rep(json).ok_or_else(unsupported)? {
             Json::String(id) => self.build_funcall(id),
             Json::Array(arr) => match &arr[..] {
-                [Json::Number(n), num]
-                    if *n == serde_json::Number::from(4u32) =>
+                [Json::Number(n), Json::String(num)]
+                    if *n == serde_json::Number::from(4u32)
+                        || *n == serde_json::Number::from(5u32) =>
                 {
-                    let num = match num {
-                        Json::String(s) => serde_json::from_str(s)
-                            .expect("could not parse number"),
-                        _ => todo!(),
-                    };
+                    let num =
+                        serde_json::from_str(num).map_err(|_| unsupported())?;
                     Ok(Expr::Lit(Value::Num(num)))
                 }
-                [Json::Number(n), num]
-                    if *n == serde_json::Number::from(5u32) =>
+                [Json::Number(n), Json::String(num)]
+                    if *n == serde_json::Number::from(6u32) =>
                 {
-                    let num = match num {
-                        Json::String(s) => serde_json::from_str(s)
-                            .expect("could not parse positive number"),
-                        _ => todo!(),
-                    };
+                    let num =
+                        num.parse::<u64>().map_err(|_| unsupported())? as f64;
                     Ok(Expr::Lit(Value::Num(num)))
                 }
-                [Json::Number(n), num]
-                    if *n == serde_json::Number::from(6u32) =>
+                // Integer and angle inputs, where list indices like "last"
+                // can be typed in as well.
+                [Json::Number(n), Json::String(s)]
+                    if *n == serde_json::Number::from(7u32)
+                        || *n == serde_json::Number::from(8u32) =>
                 {
-                    let num = match num {
-                        Json::String(s) => s
-                            .parse::<u64>()
-                            .expect("could not parse positive integer")
-                            as f64,
-                        _ => todo!(),
-                    };
-                    Ok(Expr::Lit(Value::Num(num)))
+                    Ok(Expr::Lit(s.parse().map_or_else(
+                        |_| Value::String((**s).into()),
+                        Value::Num,
+                    )))
                 }
-                [Json::Number(n), s]
+                [Json::Number(n), Json::String(s)]
                     if *n == serde_json::Number::from(10u32) =>
                 {
-                    let Json::String(s) = s else {
-                        todo!();
-                    };
                     Ok(Expr::Lit(Value::String((**s).into())))
                 }
+                [Json::Number(n), Json::String(name), Json::String(_)]
+                    if *n == serde_json::Number::from(11u32) =>
+                {
+                    // Broadcasts are referred to by name at runtime.
+                    Ok(Expr::Lit(Value::String((**name).into())))
+                }
                 [Json::Number(n), Json::String(_), Json::String(var_id)]
                     if *n == serde_json::Number::from(12u32) =>
                 {
@@ -348,12 +487,16 @@ impl<'a> DeCtx<'a> {
                         var_id: (**var_id).into(),
                     })
                 }
-                arr => {
-                    dbg!(arr);
-                    todo!()
+                [Json::Number(n), Json::String(_), Json::String(list_id)]
+                    if *n == serde_json::Number::from(13u32) =>
+                {
+                    Ok(Expr::ListContents {
+                        list_id: (**list_id).into(),
+                    })
                 }
+                _ => Err(unsupported()),
             },
-            _ => todo!(),
+            _ => Err(unsupported()),
         }
     }
 
@@ -365,6 +508,10 @@ impl<'a> DeCtx<'a> {
                 let name = str_field(block, "VALUE")?.into();
                 Ok(Expr::ProcArgStringNumber { name })
             }
+            "data_variable" => {
+                let var_id = var_list_field(block, "VARIABLE")?.into();
+                Ok(Expr::GetVar { var_id })
+            }
             "data_itemoflist" => {
                 let index = self.input(block, "INDEX")?;
                 let list_id = var_list_field(block, "LIST")?.into();
@@ -373,36 +520,51 @@ impl<'a> DeCtx<'a> {
                     index: Box::new(index),
                 })
             }
+            "data_itemnumoflist" => {
+                let item = self.input(block, "ITEM")?;
+                let list_id = var_list_field(block, "LIST")?.into();
+                Ok(Expr::ItemNumOfList {
+                    list_id,
+                    item: Box::new(item),
+                })
+            }
             "data_lengthoflist" => {
                 let list_id = var_list_field(block, "LIST")?.into();
                 Ok(Expr::LengthOfList { list_id })
             }
+            "data_listcontents" => {
+                let list_id = var_list_field(block, "LIST")?.into();
+                Ok(Expr::ListContents { list_id })
+            }
+            "sensing_keyoptions" => {
+                let key = str_field(block, "KEY_OPTION")?;
+                Ok(Expr::Lit(Value::String(key.into())))
+            }
+            "sensing_of" => {
+                let property = str_field(block, "PROPERTY")?.into();
+                let object = Box::new(self.input(block, "OBJECT")?);
+                Ok(Expr::AttributeOf { property, object })
+            }
+            "sensing_of_object_menu" => {
+                let object = str_field(block, "OBJECT")?;
+                Ok(Expr::Lit(Value::String(object.into())))
+            }
+            "control_create_clone_of_menu" => {
+                let option = str_field(block, "CLONE_OPTION")?;
+                Ok(Expr::Lit(Value::String(option.into())))
+            }
             "operator_mathop" => {
-                let operator = str_field(block, "OPERATOR")?;
-                let num = self.input(block, "NUM")?;
-                match operator {
-                    "abs" => Ok(Expr::Abs(Box::new(num))),
-                    "floor" => Ok(Expr::Floor(Box::new(num))),
-                    "ceiling" => Ok(Expr::Ceiling(Box::new(num))),
-                    "sqrt" => Ok(Expr::Sqrt(Box::new(num))),
-                    "sin" => Ok(Expr::Sin(Box::new(num))),
-                    "cos" => Ok(Expr::Cos(Box::new(num))),
-                    "tan" => Ok(Expr::Tan(Box::new(num))),
-                    "asin" => Ok(Expr::Asin(Box::new(num))),
-                    "acos" => Ok(Expr::Acos(Box::new(num))),
-                    "atan" => Ok(Expr::Atan(Box::new(num))),
-                    "ln" => Ok(Expr::Ln(Box::new(num))),
-                    "log" => Ok(Expr::Log(Box::new(num))),
-                    "e ^" => Ok(Expr::EExp(Box::new(num))),
-                    "10 ^" => Ok(Expr::TenExp(Box::new(num))),
-                    _ => todo!(),
-                }
+                // Unknown operators are only rejected when evaluated so that
+                // projects using newer ones can still be loaded.
+                let op = str_field(block, "OPERATOR")?.into();
+                let num = Box::new(self.input(block, "NUM")?);
+                Ok(Expr::MathOp { op, num })
             }
             opcode => {
-                // Field generation has to be done manually for each opcode that uses it
-                if !block.fields.is_empty() {
-                    dbg!(block);
-                    todo!();
+                if is_statement(opcode) {
+                    return Err(DeError::StatementAsReporter(
+                        opcode.to_owned(),
+                    ));
                 }
 
                 let inputs = block
@@ -413,6 +575,7 @@ impl<'a> DeCtx<'a> {
                 Ok(Expr::Call {
                     opcode: opcode.to_string(),
                     inputs,
+                    fields: fields(block)?,
                 })
             }
         }
@@ -427,7 +590,7 @@ impl<'a> DeCtx<'a> {
         )
     }
 
-    fn get(&self, id: &str) -> DeResult<&Block> {
+    fn get(&self, id: &str) -> DeResult<&Block<'_>> {
         self.blocks
             .get(id)
             .ok_or_else(|| DeError::NonExsistentID(id.to_owned()))
@@ -436,12 +599,39 @@ impl<'a> DeCtx<'a> {
     fn substack(&self, block: &Block, name: &str) -> DeResult<Statement> {
         match block.inputs.get(name).and_then(get_rep) {
             Some(Json::String(id)) => self.build_statement(id),
-            Some(Json::Null) | None => Ok(Statement::Do(Vec::new())),
-            _ => todo!(),
+            Some(Json::Null) | None => Ok(Statement::Do(Rc::new([]))),
+            Some(json) => Err(DeError::UnsupportedInput(json.to_string())),
         }
     }
 }
 
+/// Whether `opcode` belongs to a block that can only be used as a statement.
+fn is_statement(opcode: &str) -> bool {
+    is_builtin_statement(opcode)
+        || matches!(
+            opcode,
+            "control_if"
+                | "control_if_else"
+                | "control_repeat"
+                | "control_forever"
+                | "control_repeat_until"
+                | "control_while"
+                | "control_for_each"
+                | "control_stop"
+                | "procedures_call"
+                | "data_deletealloflist"
+                | "data_deleteoflist"
+                | "data_addtolist"
+                | "data_insertatlist"
+                | "data_replaceitemoflist"
+                | "data_setvariableto"
+                | "data_changevariableby"
+        )
+}
+
+/// Returns what is on top of an input. An input that has a reporter or a
+/// variable dropped into it keeps the shadow that it replaced as a third
+/// element, which is ignored.
 fn get_rep(json: &Json) -> Option<&Json> {
     let arr = json.as_array()?;
     match &arr[..] {
@@ -450,26 +640,243 @@ fn get_rep(json: &Json) -> Option<&Json> {
     }
 }
 
+/// The values of every field of `block`, for blocks that don't get special
+/// treatment.
+fn fields(block: &Block) -> DeResult<HashMap<EcoString, Value>> {
+    block
+        .fields
+        .iter()
+        .map(|(name, field)| {
+            let value = match field.as_array().map(Vec::as_slice) {
+                Some([Json::String(s), ..]) => Value::String((**s).into()),
+                Some([Json::Number(n), ..]) => {
+                    Value::Num(n.as_f64().unwrap_or_default())
+                }
+                _ => return Err(DeError::InvalidField((**name).to_owned())),
+            };
+            Ok(((**name).into(), value))
+        })
+        .collect()
+}
+
 fn var_list_field<'blk>(block: &'blk Block, name: &str) -> DeResult<&'blk str> {
-    let arr = block
+    match block
         .fields
         .get(name)
         .and_then(Json::as_array)
-        .expect("invalid field");
-    match &arr[..] {
-        [Json::String(_), Json::String(id)] => Ok(id),
-        _ => todo!(),
+        .map(Vec::as_slice)
+    {
+        Some([Json::String(_), Json::String(id)]) => Ok(id),
+        _ => Err(DeError::InvalidField(name.to_owned())),
     }
 }
 
 fn str_field<'blk>(block: &'blk Block, name: &str) -> DeResult<&'blk str> {
-    let arr = block
+    // Some dropdowns, such as that of broadcasts, also store an ID.
+    match block
         .fields
         .get(name)
         .and_then(Json::as_array)
-        .expect("invalid field");
-    match &arr[..] {
-        [Json::String(s), Json::Null] => Ok(s),
-        _ => todo!(),
+        .map(Vec::as_slice)
+    {
+        Some([Json::String(s), _]) => Ok(s),
+        _ => Err(DeError::InvalidField(name.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{LevelFilter, Log, Metadata, Record};
+    use serde_json::json;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static LOGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Collects the messages logged on the current thread, so that tests
+    /// running in parallel don't see each other's warnings.
+    struct TestLogger;
+
+    impl Log for TestLogger {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            LOGS.with(|logs| logs.borrow_mut().push(record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: TestLogger = TestLogger;
+
+    fn capture_logs(f: impl FnOnce()) -> Vec<String> {
+        log::set_logger(&LOGGER).ok();
+        log::set_max_level(LevelFilter::Warn);
+        LOGS.with(|logs| logs.borrow_mut().clear());
+        f();
+        LOGS.with(RefCell::take)
+    }
+
+    fn build_procs(blocks: &Json) -> DeResult<Procs> {
+        let source = blocks.to_string();
+        let blocks = serde_json::from_str(&source).expect("invalid blocks");
+        DeCtx::new(blocks).build_procs()
+    }
+
+    #[test]
+    fn unsupported_hat_is_warned_about() {
+        let logs = capture_logs(|| {
+            build_procs(&json!({
+                "hat": {
+                    "opcode": "event_whengreaterthan",
+                    "next": "say",
+                    "topLevel": true,
+                },
+                "say": { "opcode": "looks_say", "inputs": {} },
+            }))
+            .expect("loading failed");
+        });
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].contains("event_whengreaterthan"));
+    }
+
+    #[test]
+    fn unsupported_stop_option_is_an_error() {
+        let res = build_procs(&json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "stop" },
+            "stop": {
+                "opcode": "control_stop",
+                "fields": { "STOP_OPTION": ["everything else", null] },
+            },
+        }));
+        assert!(matches!(res, Err(DeError::UnsupportedStopOption(_))));
+    }
+
+    /// A custom block definition whose prototype has `mutation`.
+    fn custom_block(mutation: &Json) -> Json {
+        json!({
+            "define": {
+                "opcode": "procedures_definition",
+                "next": "say",
+                "inputs": { "custom_block": [1, "proto"] },
+            },
+            "proto": {
+                "opcode": "procedures_prototype",
+                "mutation": mutation,
+            },
+            "say": { "opcode": "looks_say", "inputs": {} },
+        })
+    }
+
+    #[test]
+    fn malformed_custom_blocks_are_errors() {
+        let mut missing_prototype = custom_block(&json!({}));
+        missing_prototype["define"]["inputs"] = json!({});
+        assert!(matches!(
+            build_procs(&missing_prototype),
+            Err(DeError::MissingInput(_))
+        ));
+
+        let missing_proccode = custom_block(&json!({
+            "argumentids": "[]",
+            "argumentnames": "[]",
+        }));
+        assert!(matches!(
+            build_procs(&missing_proccode),
+            Err(DeError::MissingMutationField(field)) if field == "proccode"
+        ));
+
+        let missing_ids = custom_block(&json!({
+            "proccode": "f",
+            "argumentnames": "[]",
+        }));
+        assert!(matches!(
+            build_procs(&missing_ids),
+            Err(DeError::MissingMutationField(field)) if field == "argumentids"
+        ));
+
+        let invalid_names = custom_block(&json!({
+            "proccode": "f %s",
+            "argumentids": "[\"a\"]",
+            "argumentnames": "[\"a\"",
+        }));
+        assert!(matches!(
+            build_procs(&invalid_names),
+            Err(DeError::InvalidMutationField(field))
+                if field == "argumentnames"
+        ));
+    }
+
+    #[test]
+    fn malformed_substacks_and_fields_are_errors() {
+        let res = build_procs(&json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "forever" },
+            "forever": {
+                "opcode": "control_forever",
+                "inputs": { "SUBSTACK": [2, 42] },
+            },
+        }));
+        assert!(matches!(res, Err(DeError::UnsupportedInput(_))));
+
+        let res = build_procs(&json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "set" },
+            "set": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["no ID"] },
+                "inputs": { "VALUE": [1, [10, ""]] },
+            },
+        }));
+        assert!(matches!(res, Err(DeError::InvalidField(_))));
+
+        let res = build_procs(&json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "stop" },
+            "stop": { "opcode": "control_stop" },
+        }));
+        assert!(matches!(res, Err(DeError::InvalidField(_))));
+    }
+
+    #[test]
+    fn variable_reporters_refer_to_variables_by_id() {
+        let source = json!({
+            "var": {
+                "opcode": "data_variable",
+                "fields": { "VARIABLE": ["score", "score-id"] },
+            },
+        })
+        .to_string();
+        let blocks = serde_json::from_str(&source).expect("invalid blocks");
+        let expr = DeCtx::new(blocks)
+            .build_expr(&json!([3, "var", [10, ""]]))
+            .expect("loading failed");
+        assert!(
+            matches!(expr, Expr::GetVar { var_id } if var_id == "score-id")
+        );
+    }
+
+    #[test]
+    fn statements_used_as_reporters_are_errors() {
+        let res = build_procs(&json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "say" },
+            "say": {
+                "opcode": "looks_say",
+                "inputs": { "MESSAGE": [3, "if", [10, ""]] },
+            },
+            "if": { "opcode": "control_if", "inputs": {} },
+        }));
+        let Err(err) = res else {
+            panic!("loading should have failed");
+        };
+        assert!(matches!(
+            &err,
+            DeError::StatementAsReporter(op) if op == "control_if"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "statement block `control_if` was used as a reporter"
+        );
     }
 }
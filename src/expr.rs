@@ -1,6 +1,9 @@
 use ecow::EcoString;
 use sb3_stuff::Value;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt::{self, Write as _},
+};
 
 #[derive(Debug)]
 pub enum Expr {
@@ -15,25 +18,130 @@ pub enum Expr {
         list_id: EcoString,
         index: Box<Self>,
     },
+    /// The position of the first item of the list that equals `item`,
+    /// counting from 1, or 0 if there is none.
+    ItemNumOfList {
+        list_id: EcoString,
+        item: Box<Self>,
+    },
     LengthOfList {
         list_id: EcoString,
     },
-    Abs(Box<Self>),
-    Floor(Box<Self>),
-    Ceiling(Box<Self>),
-    Sqrt(Box<Self>),
-    Sin(Box<Self>),
-    Cos(Box<Self>),
-    Tan(Box<Self>),
-    Asin(Box<Self>),
-    Acos(Box<Self>),
-    Atan(Box<Self>),
-    Ln(Box<Self>),
-    Log(Box<Self>),
-    EExp(Box<Self>),
-    TenExp(Box<Self>),
+    ListContents {
+        list_id: EcoString,
+    },
+    MathOp {
+        op: EcoString,
+        num: Box<Self>,
+    },
+    AttributeOf {
+        property: EcoString,
+        object: Box<Self>,
+    },
     Call {
         opcode: String,
         inputs: HashMap<EcoString, Self>,
+        /// Dropdowns that aren't inputs, by name.
+        fields: HashMap<EcoString, Value>,
     },
 }
+
+impl Expr {
+    /// Calls `f` on this expression and every expression nested inside it.
+    pub fn walk(&self, f: &mut impl FnMut(&Self)) {
+        f(self);
+        match self {
+            Self::ItemOfList { index: expr, .. }
+            | Self::ItemNumOfList { item: expr, .. }
+            | Self::MathOp { num: expr, .. }
+            | Self::AttributeOf { object: expr, .. } => expr.walk(f),
+            Self::Call { inputs, .. } => {
+                for input in inputs.values() {
+                    input.walk(f);
+                }
+            }
+            Self::Lit(_)
+            | Self::GetVar { .. }
+            | Self::ProcArgStringNumber { .. }
+            | Self::LengthOfList { .. }
+            | Self::ListContents { .. } => {}
+        }
+    }
+}
+
+/// Renders the expression as Scratch-like pseudocode, with strings quoted and
+/// variables and lists shown by ID in square brackets.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Lit(Value::String(s)) => write!(f, "{:?}", s.as_str()),
+            Self::Lit(lit) => write!(f, "{lit}"),
+            Self::GetVar { var_id } => write!(f, "[{var_id}]"),
+            Self::ProcArgStringNumber { name } => write!(f, "({name})"),
+            Self::ItemOfList { list_id, index } => {
+                write!(f, "(item {index} of [{list_id}])")
+            }
+            Self::ItemNumOfList { list_id, item } => {
+                write!(f, "(item # of {item} in [{list_id}])")
+            }
+            Self::LengthOfList { list_id } => {
+                write!(f, "(length of [{list_id}])")
+            }
+            Self::ListContents { list_id } => {
+                write!(f, "(contents of [{list_id}])")
+            }
+            Self::MathOp { op, num } => write!(f, "({op} of {num})"),
+            Self::AttributeOf { property, object } => {
+                write!(f, "({property:?} of {object})")
+            }
+            Self::Call {
+                opcode,
+                inputs,
+                fields,
+            } => {
+                write!(f, "{opcode}")?;
+                fmt_inputs(f, inputs)?;
+                fmt_fields(f, fields)
+            }
+        }
+    }
+}
+
+/// Writes inputs as `(NAME: value, ...)`, sorted by name so that the output
+/// is deterministic.
+pub fn fmt_inputs(
+    f: &mut fmt::Formatter<'_>,
+    inputs: &HashMap<EcoString, Expr>,
+) -> fmt::Result {
+    let mut inputs = inputs.iter().collect::<Vec<_>>();
+    inputs.sort_unstable_by_key(|&(name, _)| name);
+    f.write_char('(')?;
+    for (i, (name, expr)) in inputs.into_iter().enumerate() {
+        if i != 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{name}: {expr}")?;
+    }
+    f.write_char(')')
+}
+
+/// Writes fields as `[NAME: "value", ...]`, sorted by name, or nothing if
+/// there are none.
+pub fn fmt_fields(
+    f: &mut fmt::Formatter<'_>,
+    fields: &HashMap<EcoString, Value>,
+) -> fmt::Result {
+    if fields.is_empty() {
+        return Ok(());
+    }
+    let mut fields = fields.iter().collect::<Vec<_>>();
+    fields.sort_unstable_by_key(|&(name, _)| name);
+    f.write_char('[')?;
+    for (i, (name, value)) in fields.into_iter().enumerate() {
+        if i != 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{name}: {:?}", &*value.to_cow_str())?;
+    }
+    f.write_char(']')
+}
@@ -0,0 +1,50 @@
+use std::{fmt, io::BufRead};
+
+/// Provides the state of the keyboard, mouse and microphone to the sensing
+/// blocks. This is what lets a frontend or a test drive a project's input.
+pub trait InputSource: fmt::Debug {
+    /// Whether the key with the given Scratch name (such as `"space"`,
+    /// `"left arrow"`, `"a"` or `"any"`) is currently held down.
+    fn key_pressed(&self, key: &str) -> bool;
+
+    /// The mouse position in stage coordinates.
+    fn mouse_pos(&self) -> (f64, f64);
+
+    fn mouse_down(&self) -> bool;
+
+    /// The microphone loudness on Scratch's scale from 0 to 100, or -1 if
+    /// there is no microphone.
+    fn loudness(&self) -> f64;
+}
+
+/// The input source used when none has been set: no keys are pressed, the
+/// mouse rests at the center of the stage and there is no microphone.
+#[derive(Debug)]
+pub struct NoInput;
+
+impl InputSource for NoInput {
+    fn key_pressed(&self, _key: &str) -> bool {
+        false
+    }
+
+    fn mouse_pos(&self) -> (f64, f64) {
+        (0.0, 0.0)
+    }
+
+    fn mouse_down(&self) -> bool {
+        false
+    }
+
+    fn loudness(&self) -> f64 {
+        -1.0
+    }
+}
+
+/// Where the VM reads the answers to "ask and wait" blocks from.
+pub struct Answers(pub Box<dyn BufRead>);
+
+impl fmt::Debug for Answers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Answers").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,33 @@
+#![forbid(unsafe_code)]
+#![warn(clippy::unwrap_used, clippy::pedantic, clippy::nursery)]
+#![allow(
+    clippy::too_many_lines,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss,
+    clippy::must_use_candidate,
+    clippy::missing_errors_doc
+)]
+
+pub mod clock;
+mod deser;
+mod expr;
+pub mod input;
+pub mod output;
+pub mod prelude;
+mod proc;
+pub mod snapshot;
+mod sprite;
+mod statement;
+mod thread;
+mod value;
+mod vm;
+
+pub use deser::DeError;
+pub use expr::Expr;
+pub use sb3_stuff::{Index, Value};
+pub use thread::ThreadInfo;
+pub use value::ValueExt;
+pub use vm::{
+    Event, IndexResolution, LoadError, RunReport, StopHandle, VMError, VM,
+};
@@ -1,21 +1,16 @@
 #![forbid(unsafe_code)]
 #![warn(clippy::unwrap_used, clippy::pedantic, clippy::nursery)]
-#![allow(
-    clippy::too_many_lines,
-    clippy::cast_possible_truncation,
-    clippy::cast_sign_loss,
-    clippy::cast_precision_loss
-)]
-
-use crate::vm::VM;
-use std::{fs::File, process::ExitCode};
-
-mod deser;
-mod expr;
-mod proc;
-mod sprite;
-mod statement;
-mod vm;
+
+use env_logger::Env;
+use log::{error, warn};
+use std::{
+    fs::File,
+    path::Path,
+    process::ExitCode,
+    sync::atomic::{AtomicBool, Ordering::Relaxed},
+    time::Duration,
+};
+use unsb3::{clock::VirtualClock, VM};
 
 fn main() -> ExitCode {
     match real_main() {
@@ -25,20 +20,114 @@ fn main() -> ExitCode {
 }
 
 fn real_main() -> Result<(), ()> {
-    let path = std::env::args().nth(1);
+    env_logger::Builder::from_env(Env::default().default_filter_or("warn"))
+        .format_timestamp(None)
+        .init();
+
+    let mut strict = false;
+    let mut list_unsupported = false;
+    let mut dump_ir = false;
+    let mut fencing = false;
+    let mut virtual_time = false;
+    let mut max_string_len = None;
+    let mut timeout = None;
+    let mut seed = None;
+    let mut path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--strict" => strict = true,
+            "--list-unsupported" => list_unsupported = true,
+            "--dump-ir" => dump_ir = true,
+            "--fencing" => fencing = true,
+            "--virtual-time" => virtual_time = true,
+            "--max-string-len" => {
+                let len = args
+                    .next()
+                    .and_then(|len| len.parse().ok())
+                    .ok_or_else(|| {
+                        error!("`--max-string-len` requires a length");
+                    })?;
+                max_string_len = Some(len);
+            }
+            "--timeout" => {
+                let secs = args
+                    .next()
+                    .and_then(|secs| secs.parse().ok())
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .ok_or_else(|| {
+                        error!("`--timeout` requires a number of seconds");
+                    })?;
+                timeout = Some(secs);
+            }
+            "--seed" => {
+                let n = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| error!("`--seed` requires a number"))?;
+                seed = Some(n);
+            }
+            _ => path = Some(arg),
+        }
+    }
     let path = path.as_deref().unwrap_or("project.sb3");
 
-    let file = File::open(path).map_err(|err| eprintln!("IO error: {err}"))?;
+    let path = Path::new(path);
+    let mut vm = if path.is_dir() {
+        VM::from_project_dir(path)
+    } else {
+        let file = File::open(path).map_err(|err| error!("IO error: {err}"))?;
+        VM::from_reader(file)
+    }
+    .map_err(|err| error!("{err}"))?;
+    if let Some(seed) = seed {
+        vm = vm.with_rng_seed(seed);
+    }
+    vm.set_max_string_len(max_string_len);
+    vm.set_fencing(fencing);
+    if virtual_time {
+        vm.set_clock(Box::new(VirtualClock::default()));
+    }
+
+    if list_unsupported {
+        for opcode in vm.unsupported_opcodes() {
+            println!("{opcode}");
+        }
+        return Ok(());
+    }
 
-    let mut archive = zip::ZipArchive::new(file)
-        .map_err(|err| eprintln!("Zip error: {err}"))?;
+    if dump_ir {
+        print!("{}", vm.dump_ir());
+        return Ok(());
+    }
 
-    let project_json = archive
-        .by_name("project.json")
-        .map_err(|err| eprintln!("Zip error: {err}"))?;
+    if strict {
+        vm.check_opcodes()
+            .map_err(|err| error!("Deserialization error: {err}"))?;
+        let empty_forever_loops = vm.count_empty_forever_loops();
+        if empty_forever_loops != 0 {
+            warn!(
+                "found {empty_forever_loops} `forever` loop(s) with an empty \
+                 body"
+            );
+        }
+    }
 
-    let vm: VM = serde_json::from_reader(project_json)
-        .map_err(|err| eprintln!("Deserialization error: {err}"))?;
+    // Pressing Ctrl-C stops the project like the stop button would, so that
+    // scripts stuck in a `forever` loop still unwind cleanly. Pressing it
+    // again exits right away, since the project might be blocked on reading
+    // an answer and never get to stop.
+    let stop_handle = vm.stop_handle();
+    let pressed = AtomicBool::new(false);
+    ctrlc::set_handler(move || {
+        if pressed.swap(true, Relaxed) {
+            std::process::exit(130);
+        }
+        stop_handle.request_stop();
+    })
+    .map_err(|err| error!("could not set Ctrl-C handler: {err}"))?;
 
-    vm.run().map_err(|err| eprintln!("VM error: {err}"))
+    timeout
+        .map_or_else(|| vm.run(), |timeout| vm.run_with_timeout(timeout))
+        .map_err(|err| error!("VM error: {err}"))
 }
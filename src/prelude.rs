@@ -0,0 +1,6 @@
+//! Everything needed to load and run a project and to inspect the results,
+//! for glob importing.
+
+pub use crate::{
+    value::ValueExt, DeError, Index, LoadError, VMError, Value, VM,
+};
@@ -1,16 +1,86 @@
 use crate::statement::Statement;
 use ecow::EcoString;
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, rc::Rc};
 
 #[derive(Debug)]
 pub struct Custom {
     pub arg_names_by_id: HashMap<EcoString, EcoString>,
-    pub body: Statement,
+    pub body: Rc<Statement>,
+    /// Whether the procedure runs without screen refresh, which means that
+    /// its loops don't yield.
+    pub warp: bool,
 }
 
 #[derive(Debug)]
 pub struct Procs {
-    pub when_flag_clicked: Vec<Statement>,
+    pub when_flag_clicked: Vec<Rc<Statement>>,
+    pub start_as_clone: Vec<Rc<Statement>>,
     pub custom: HashMap<String, Custom>,
-    pub broadcasts: HashMap<String, Vec<Statement>>,
+    pub broadcasts: HashMap<String, Vec<Rc<Statement>>>,
+    /// Scripts under "when key pressed" hats by the name of their key, which
+    /// is "any" for those that fire on every key.
+    pub when_key_pressed: HashMap<String, Vec<Rc<Statement>>>,
+    /// Scripts under "when this sprite clicked" or "when stage clicked" hats.
+    pub when_clicked: Vec<Rc<Statement>>,
+}
+
+impl Procs {
+    /// Iterates over the bodies of every script and custom procedure.
+    pub fn scripts(&self) -> impl Iterator<Item = &Statement> {
+        self.when_flag_clicked
+            .iter()
+            .chain(&self.start_as_clone)
+            .chain(self.custom.values().map(|custom| &custom.body))
+            .chain(self.broadcasts.values().flatten())
+            .chain(self.when_key_pressed.values().flatten())
+            .chain(&self.when_clicked)
+            .map(|script| &**script)
+    }
+}
+
+/// Renders every script as pseudocode, separated by blank lines.
+impl fmt::Display for Procs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut custom = self.custom.iter().collect::<Vec<_>>();
+        custom.sort_unstable_by_key(|&(proccode, _)| proccode);
+        let mut broadcasts = self.broadcasts.iter().collect::<Vec<_>>();
+        broadcasts.sort_unstable_by_key(|&(name, _)| name);
+        let mut when_key_pressed =
+            self.when_key_pressed.iter().collect::<Vec<_>>();
+        when_key_pressed.sort_unstable_by_key(|&(key, _)| key);
+
+        let hats =
+            self.when_flag_clicked
+                .iter()
+                .map(|script| ("when flag clicked".to_owned(), script))
+                .chain(self.start_as_clone.iter().map(|script| {
+                    ("when I start as a clone".to_owned(), script)
+                }))
+                .chain(custom.into_iter().map(|(proccode, custom)| {
+                    (format!("define {proccode:?}"), &custom.body)
+                }))
+                .chain(broadcasts.into_iter().flat_map(|(name, scripts)| {
+                    scripts.iter().map(move |script| {
+                        (format!("when I receive {name:?}"), script)
+                    })
+                }))
+                .chain(when_key_pressed.into_iter().flat_map(
+                    |(key, scripts)| {
+                        scripts.iter().map(move |script| {
+                            (format!("when {key:?} key pressed"), script)
+                        })
+                    },
+                ))
+                .chain(self.when_clicked.iter().map(|script| {
+                    ("when this sprite clicked".to_owned(), script)
+                }));
+        for (i, (hat, script)) in hats.enumerate() {
+            if i != 0 {
+                f.write_str("\n\n")?;
+            }
+            write!(f, "{hat}")?;
+            script.fmt_body(f, 0)?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,48 @@
+use ecow::EcoString;
+use sb3_stuff::Value;
+use std::collections::HashMap;
+
+/// The contents of every variable and list at one point in time, keyed by
+/// ID.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub vars: HashMap<EcoString, Value>,
+    pub lists: HashMap<EcoString, Vec<Value>>,
+}
+
+impl Snapshot {
+    /// Returns the IDs of the variables and lists whose contents differ
+    /// between `self` and `other`, in sorted order.
+    pub fn diff(&self, other: &Self) -> Vec<EcoString> {
+        let mut changed = changed_keys(&self.vars, &other.vars, same_value);
+        changed.extend(changed_keys(&self.lists, &other.lists, |a, b| {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_value(a, b))
+        }));
+        changed.sort();
+        changed
+    }
+}
+
+fn changed_keys<T>(
+    old: &HashMap<EcoString, T>,
+    new: &HashMap<EcoString, T>,
+    same: impl Fn(&T, &T) -> bool,
+) -> Vec<EcoString> {
+    old.iter()
+        .filter(|&(id, old)| new.get(id).is_none_or(|new| !same(old, new)))
+        .map(|(id, _)| id)
+        .chain(new.keys().filter(|id| !old.contains_key(*id)))
+        .cloned()
+        .collect()
+}
+
+/// Whether two values are identical, as opposed to merely comparing equal in
+/// Scratch, where for example `"1"` equals `1` and `"a"` equals `"A"`.
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Num(a), Value::Num(b)) => a.to_bits() == b.to_bits(),
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        _ => false,
+    }
+}
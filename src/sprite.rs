@@ -1,50 +1,227 @@
 use crate::{
-    deser::{Block, DeCtx},
+    deser::{BlockEntry, DeCtx, DeError},
     proc::Procs,
 };
 use ecow::EcoString;
-use serde::{de::Error, Deserialize, Deserializer};
-use std::{cell::Cell, collections::HashMap};
+use sb3_stuff::Value;
+use serde::Deserialize;
+use serde_json::Value as Json;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
 
+/// A running instance of a target. Clones share their scripts with the
+/// sprite they were cloned from but have their own position and direction.
 #[derive(Debug)]
 pub struct Sprite {
-    pub procs: Procs,
+    /// The name of the target, which clones share with their original.
+    pub name: EcoString,
+    pub procs: Rc<Procs>,
+    pub is_stage: bool,
+    pub is_clone: bool,
+    /// Set once a clone has been deleted so that scripts which are about to
+    /// start on it can be skipped.
+    pub deleted: Cell<bool>,
     pub x: Cell<f64>,
     pub y: Cell<f64>,
+    /// The direction in degrees, where 90 points right and 0 points up.
+    pub direction: Cell<f64>,
+    pub visible: Cell<bool>,
+    /// The size as a percentage of the costume's own size.
+    pub size: Cell<f64>,
+    /// The names of the sprite's costumes, in order.
+    pub costumes: Rc<[EcoString]>,
+    /// The index of the current costume.
+    pub costume: Cell<usize>,
+    /// Variables that are local to this sprite. Those of the stage are
+    /// global and are stored in the VM instead.
+    pub vars: RefCell<HashMap<EcoString, Value>>,
+    /// Lists that are local to this sprite.
+    pub lists: RefCell<HashMap<EcoString, Vec<Value>>>,
+    pub variable_ids_by_name: Rc<HashMap<EcoString, EcoString>>,
+}
+
+impl Sprite {
+    /// Creates a clone with the same state as this sprite, including copies
+    /// of its local variables and lists.
+    pub fn make_clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            procs: Rc::clone(&self.procs),
+            is_stage: self.is_stage,
+            is_clone: true,
+            deleted: Cell::new(false),
+            x: self.x.clone(),
+            y: self.y.clone(),
+            direction: self.direction.clone(),
+            visible: self.visible.clone(),
+            size: self.size.clone(),
+            costumes: Rc::clone(&self.costumes),
+            costume: self.costume.clone(),
+            vars: self.vars.clone(),
+            lists: self.lists.clone(),
+            variable_ids_by_name: Rc::clone(&self.variable_ids_by_name),
+        }
+    }
+}
+
+/// A target (sprite or stage) as it is stored in `project.json`.
+#[derive(Deserialize)]
+pub struct DeSprite<'a> {
+    pub name: EcoString,
+    #[serde(rename = "isStage")]
+    #[serde(default)]
+    pub is_stage: bool,
+    #[serde(borrow)]
+    blocks: HashMap<EcoString, BlockEntry<'a>>,
+    #[serde(default)]
+    x: f64,
+    #[serde(default)]
+    y: f64,
+    #[serde(default = "default_direction")]
+    direction: f64,
+    #[serde(default = "default_visible")]
+    visible: bool,
+    #[serde(default = "default_size")]
+    size: f64,
+    #[serde(default)]
+    costumes: Vec<DeCostume>,
+    #[serde(rename = "currentCostume")]
+    #[serde(default)]
+    current_costume: usize,
+    pub tempo: Option<f64>,
+    #[serde(default)]
+    pub variables: HashMap<EcoString, DeVariable>,
+    #[serde(default)]
+    pub lists: HashMap<EcoString, DeList>,
+}
+
+const fn default_direction() -> f64 {
+    90.0
+}
+
+const fn default_visible() -> bool {
+    true
+}
+
+const fn default_size() -> f64 {
+    100.0
+}
+
+#[derive(Deserialize)]
+struct DeCostume {
+    name: EcoString,
+}
+
+/// A variable declaration, which is stored in `project.json` as an array of
+/// the name and the initial value.
+#[derive(Deserialize)]
+#[serde(try_from = "Vec<Json>")]
+pub struct DeVariable {
+    pub name: EcoString,
+    pub value: Value,
 }
 
-pub fn deserialize_sprites<'de, D>(
-    deserializer: D,
-) -> Result<HashMap<EcoString, Sprite>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    #[derive(Deserialize)]
-    struct DeSprite<'a> {
-        name: EcoString,
-        #[serde(borrow)]
-        blocks: HashMap<EcoString, Block<'a>>,
-        #[serde(default)]
-        x: f64,
-        #[serde(default)]
-        y: f64,
+impl TryFrom<Vec<Json>> for DeVariable {
+    type Error = &'static str;
+
+    fn try_from(json: Vec<Json>) -> Result<Self, Self::Error> {
+        match &json[..] {
+            [Json::String(name), value, ..] => Ok(Self {
+                name: name.as_str().into(),
+                value: json_to_value(value)
+                    .ok_or("invalid initial value for variable")?,
+            }),
+            _ => Err("invalid variable declaration"),
+        }
+    }
+}
+
+/// A list declaration, which is stored in `project.json` as an array of the
+/// name and the initial items.
+#[derive(Deserialize)]
+#[serde(try_from = "Vec<Json>")]
+pub struct DeList {
+    pub name: EcoString,
+    pub items: Vec<Value>,
+}
+
+impl TryFrom<Vec<Json>> for DeList {
+    type Error = &'static str;
+
+    fn try_from(json: Vec<Json>) -> Result<Self, Self::Error> {
+        match &json[..] {
+            [Json::String(name), Json::Array(items), ..] => Ok(Self {
+                name: name.as_str().into(),
+                items: items
+                    .iter()
+                    .map(json_to_value)
+                    .collect::<Option<_>>()
+                    .ok_or("invalid initial item for list")?,
+            }),
+            _ => Err("invalid list declaration"),
+        }
+    }
+}
+
+fn json_to_value(json: &Json) -> Option<Value> {
+    match json {
+        Json::Number(n) => n.as_f64().map(Value::Num),
+        Json::String(s) => Some(Value::String(s.as_str().into())),
+        Json::Bool(b) => Some(Value::Bool(*b)),
+        _ => None,
+    }
+}
+
+impl DeSprite<'_> {
+    /// The variables declared by this target with their initial values.
+    pub fn initial_vars(&self) -> HashMap<EcoString, Value> {
+        self.variables
+            .iter()
+            .map(|(id, var)| (id.clone(), var.value.clone()))
+            .collect()
+    }
+
+    /// The lists declared by this target with their initial items.
+    pub fn initial_lists(&self) -> HashMap<EcoString, Vec<Value>> {
+        self.lists
+            .iter()
+            .map(|(id, list)| (id.clone(), list.items.clone()))
+            .collect()
     }
 
-    let sprites = <Vec<DeSprite>>::deserialize(deserializer)?;
-
-    sprites
-        .into_iter()
-        .map(|DeSprite { name, blocks, x, y }| {
-            let ctx = DeCtx::new(blocks);
-            let procs = ctx.build_procs().map_err(D::Error::custom)?;
-            Ok((
-                name,
-                Sprite {
-                    procs,
-                    x: Cell::new(x),
-                    y: Cell::new(y),
-                },
-            ))
+    pub fn build(self) -> Result<Sprite, DeError> {
+        // The stage's variables and lists are global, so the VM owns them.
+        let (vars, lists) = if self.is_stage {
+            (HashMap::new(), HashMap::new())
+        } else {
+            (self.initial_vars(), self.initial_lists())
+        };
+        let ctx = DeCtx::new(self.blocks);
+        let procs = ctx.build_procs()?;
+        let variable_ids_by_name = self
+            .variables
+            .iter()
+            .map(|(id, var)| (var.name.clone(), id.clone()))
+            .collect();
+        Ok(Sprite {
+            name: self.name,
+            procs: Rc::new(procs),
+            is_stage: self.is_stage,
+            is_clone: false,
+            deleted: Cell::new(false),
+            x: Cell::new(self.x),
+            y: Cell::new(self.y),
+            direction: Cell::new(self.direction),
+            visible: Cell::new(self.visible),
+            size: Cell::new(self.size),
+            costumes: self.costumes.into_iter().map(|c| c.name).collect(),
+            costume: Cell::new(self.current_costume),
+            vars: RefCell::new(vars),
+            lists: RefCell::new(lists),
+            variable_ids_by_name: Rc::new(variable_ids_by_name),
         })
-        .collect()
+    }
 }
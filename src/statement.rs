@@ -1,42 +1,50 @@
-use crate::expr::Expr;
+use crate::expr::{fmt_fields, fmt_inputs, Expr};
 use ecow::EcoString;
-use std::collections::HashMap;
+use sb3_stuff::Value;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::{self, Write as _},
+    rc::Rc,
+};
 
 #[derive(Debug)]
 pub enum Statement {
     Regular {
         opcode: EcoString,
         inputs: HashMap<EcoString, Expr>,
+        /// Dropdowns that aren't inputs, by name.
+        fields: HashMap<EcoString, Value>,
     },
-    Do(Vec<Self>),
+    Do(Rc<[Self]>),
     If {
         condition: Expr,
-        if_true: Box<Self>,
+        if_true: Rc<Self>,
     },
     IfElse {
         condition: Expr,
-        if_true: Box<Self>,
-        if_false: Box<Self>,
+        if_true: Rc<Self>,
+        if_false: Rc<Self>,
     },
     Repeat {
         times: Expr,
-        body: Box<Self>,
+        body: Rc<Self>,
     },
     Forever {
-        body: Box<Self>,
+        body: Rc<Self>,
     },
     Until {
         condition: Expr,
-        body: Box<Self>,
+        body: Rc<Self>,
     },
     While {
         condition: Expr,
-        body: Box<Self>,
+        body: Rc<Self>,
     },
     For {
         counter_id: EcoString,
         times: Expr,
-        body: Box<Self>,
+        body: Rc<Self>,
     },
     ProcCall {
         proccode: String,
@@ -53,6 +61,11 @@ pub enum Statement {
         list_id: EcoString,
         item: Expr,
     },
+    InsertAtList {
+        list_id: EcoString,
+        index: Expr,
+        item: Expr,
+    },
     ReplaceItemOfList {
         list_id: EcoString,
         index: Expr,
@@ -68,4 +81,201 @@ pub enum Statement {
     },
     StopAll,
     StopThisScript,
+    /// Stops every other thread that runs on the same sprite.
+    StopOtherScripts,
+}
+
+impl Statement {
+    /// Whether this is an empty sequence of statements, such as an empty
+    /// substack.
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Self::Do(stmts) if stmts.is_empty())
+    }
+
+    /// Returns the expressions used directly by this statement, excluding
+    /// those of nested statements.
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match self {
+            Self::Regular { inputs, .. } => inputs.values().collect(),
+            Self::ProcCall { args, .. } => args.values().collect(),
+            Self::If { condition, .. }
+            | Self::IfElse { condition, .. }
+            | Self::Until { condition, .. }
+            | Self::While { condition, .. } => vec![condition],
+            Self::Repeat { times, .. } | Self::For { times, .. } => vec![times],
+            Self::DeleteOfList { index, .. } => vec![index],
+            Self::AddToList { item, .. } => vec![item],
+            Self::InsertAtList { index, item, .. }
+            | Self::ReplaceItemOfList { index, item, .. } => vec![index, item],
+            Self::SetVariable { value, .. }
+            | Self::ChangeVariableBy { value, .. } => vec![value],
+            Self::Do(_)
+            | Self::Forever { .. }
+            | Self::DeleteAllOfList { .. }
+            | Self::StopAll
+            | Self::StopThisScript
+            | Self::StopOtherScripts => Vec::new(),
+        }
+    }
+
+    /// Calls `f` on this statement and every statement nested inside it.
+    pub fn walk(&self, f: &mut impl FnMut(&Self)) {
+        self.try_walk(&mut |stmt| {
+            f(stmt);
+            Ok::<_, Infallible>(())
+        })
+        .unwrap_or_else(|never| match never {});
+    }
+
+    /// Calls `f` on this statement and every statement nested inside it,
+    /// stopping at the first error.
+    pub fn try_walk<E>(
+        &self,
+        f: &mut impl FnMut(&Self) -> Result<(), E>,
+    ) -> Result<(), E> {
+        f(self)?;
+        match self {
+            Self::Do(stmts) => {
+                stmts.iter().try_for_each(|stmt| stmt.try_walk(f))
+            }
+            Self::If { if_true, .. } => if_true.try_walk(f),
+            Self::IfElse {
+                if_true, if_false, ..
+            } => {
+                if_true.try_walk(f)?;
+                if_false.try_walk(f)
+            }
+            Self::Repeat { body, .. }
+            | Self::Forever { body }
+            | Self::Until { body, .. }
+            | Self::While { body, .. }
+            | Self::For { body, .. } => body.try_walk(f),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Renders the statement as indented, Scratch-like pseudocode.
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+impl Statement {
+    fn fmt_indented(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        depth: usize,
+    ) -> fmt::Result {
+        // A sequence is just its statements, each on its own line.
+        if !matches!(self, Self::Do(_)) {
+            write!(f, "{:1$}", "", depth * 4)?;
+        }
+        match self {
+            Self::Regular {
+                opcode,
+                inputs,
+                fields,
+            } => {
+                write!(f, "{opcode}")?;
+                fmt_inputs(f, inputs)?;
+                fmt_fields(f, fields)
+            }
+            Self::Do(stmts) => {
+                for (i, stmt) in stmts.iter().enumerate() {
+                    if i != 0 {
+                        f.write_char('\n')?;
+                    }
+                    stmt.fmt_indented(f, depth)?;
+                }
+                Ok(())
+            }
+            Self::If { condition, if_true } => {
+                write!(f, "if {condition}")?;
+                if_true.fmt_body(f, depth)
+            }
+            Self::IfElse {
+                condition,
+                if_true,
+                if_false,
+            } => {
+                write!(f, "if {condition}")?;
+                if_true.fmt_body(f, depth)?;
+                f.write_str(" else")?;
+                if_false.fmt_body(f, depth)
+            }
+            Self::Repeat { times, body } => {
+                write!(f, "repeat {times}")?;
+                body.fmt_body(f, depth)
+            }
+            Self::Forever { body } => {
+                f.write_str("forever")?;
+                body.fmt_body(f, depth)
+            }
+            Self::Until { condition, body } => {
+                write!(f, "repeat until {condition}")?;
+                body.fmt_body(f, depth)
+            }
+            Self::While { condition, body } => {
+                write!(f, "while {condition}")?;
+                body.fmt_body(f, depth)
+            }
+            Self::For {
+                counter_id,
+                times,
+                body,
+            } => {
+                write!(f, "for each [{counter_id}] up to {times}")?;
+                body.fmt_body(f, depth)
+            }
+            Self::ProcCall { proccode, args } => {
+                write!(f, "call {proccode:?}")?;
+                fmt_inputs(f, args)
+            }
+            Self::DeleteAllOfList { list_id } => {
+                write!(f, "delete all of [{list_id}]")
+            }
+            Self::DeleteOfList { list_id, index } => {
+                write!(f, "delete {index} of [{list_id}]")
+            }
+            Self::AddToList { list_id, item } => {
+                write!(f, "add {item} to [{list_id}]")
+            }
+            Self::InsertAtList {
+                list_id,
+                index,
+                item,
+            } => write!(f, "insert {item} at {index} of [{list_id}]"),
+            Self::ReplaceItemOfList {
+                list_id,
+                index,
+                item,
+            } => write!(f, "replace item {index} of [{list_id}] with {item}"),
+            Self::SetVariable { var_id, value } => {
+                write!(f, "set [{var_id}] to {value}")
+            }
+            Self::ChangeVariableBy { var_id, value } => {
+                write!(f, "change [{var_id}] by {value}")
+            }
+            Self::StopAll => f.write_str("stop all"),
+            Self::StopThisScript => f.write_str("stop this script"),
+            Self::StopOtherScripts => f.write_str("stop other scripts"),
+        }
+    }
+
+    /// Writes a braced substack whose closing brace lines up with the
+    /// statement that owns it.
+    pub fn fmt_body(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        depth: usize,
+    ) -> fmt::Result {
+        f.write_str(" {\n")?;
+        if !self.is_empty() {
+            self.fmt_indented(f, depth + 1)?;
+            f.write_char('\n')?;
+        }
+        write!(f, "{:1$}}}", "", depth * 4)
+    }
 }
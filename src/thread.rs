@@ -0,0 +1,339 @@
+use crate::{sprite::Sprite, statement::Statement};
+use ecow::EcoString;
+use sb3_stuff::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    ops::Deref,
+    rc::Rc,
+};
+
+/// A running script. Instead of recursing through native stack frames, a
+/// thread keeps track of where it is in its own stack of `Frame`s, which lets
+/// the scheduler pause it whenever it yields and resume it later.
+#[derive(Debug)]
+pub struct Thread {
+    pub id: u64,
+    /// Clones can be deleted while the thread is paused, so the thread keeps
+    /// its sprite alive until it notices.
+    pub sprite: Rc<Sprite>,
+    pub script: Rc<Statement>,
+    /// What the thread is in the middle of, innermost last. The thread has
+    /// finished once this is empty.
+    pub frames: Vec<Frame>,
+    /// The arguments of the custom procedures that the thread is inside of,
+    /// by name with the innermost call last. These are swapped into the VM
+    /// while the thread runs.
+    pub proc_args: HashMap<EcoString, Vec<Value>>,
+}
+
+impl Thread {
+    fn new(id: u64, sprite: Rc<Sprite>, script: &Rc<Statement>) -> Self {
+        let mut thread = Self {
+            id,
+            sprite,
+            script: Rc::clone(script),
+            frames: Vec::new(),
+            proc_args: HashMap::new(),
+        };
+        thread.restart();
+        thread
+    }
+
+    /// Goes back to the start of the script, which is what Scratch does when
+    /// a hat block fires while its script is still running.
+    fn restart(&mut self) {
+        self.frames.clear();
+        let script = StmtRef::Whole(Rc::clone(&self.script));
+        self.frames.push(Frame::sequence(script));
+        self.proc_args.clear();
+    }
+
+    /// Whether this thread runs `script` on `sprite`.
+    fn runs(&self, sprite: &Rc<Sprite>, script: &Rc<Statement>) -> bool {
+        Rc::ptr_eq(&self.sprite, sprite) && Rc::ptr_eq(&self.script, script)
+    }
+
+    fn info(&self) -> ThreadInfo {
+        ThreadInfo {
+            id: self.id,
+            sprite: self.sprite.name.clone(),
+            waiting: matches!(
+                self.frames.last(),
+                Some(
+                    Frame::Wait { .. }
+                        | Frame::Ask { .. }
+                        | Frame::WaitUntil { .. }
+                        | Frame::Glide { .. }
+                        | Frame::WaitForThreads { .. }
+                )
+            ),
+        }
+    }
+}
+
+/// What `VM::pending_threads` reports about a thread that hasn't finished.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadInfo {
+    pub id: u64,
+    /// The name of the sprite that the thread runs on. Clones have the same
+    /// name as the sprite they were cloned from.
+    pub sprite: EcoString,
+    /// Whether the thread is blocked on a wait, an ask, a `wait until`, a
+    /// glide or a "broadcast and wait" rather than ready to run.
+    pub waiting: bool,
+}
+
+/// A statement that a frame refers to. Scripts are reference counted so that
+/// threads can hold on to them without borrowing the sprite they belong to.
+#[derive(Debug, Clone)]
+pub enum StmtRef {
+    Whole(Rc<Statement>),
+    /// The statement at the given index of a sequence.
+    Nth(Rc<[Statement]>, usize),
+}
+
+impl StmtRef {
+    /// Returns the `n`th statement of the sequence that this refers to, where
+    /// anything other than a `Do` counts as a sequence of one statement.
+    pub fn nth(&self, n: usize) -> Option<Self> {
+        match &**self {
+            Statement::Do(stmts) => {
+                (n < stmts.len()).then(|| Self::Nth(Rc::clone(stmts), n))
+            }
+            _ => (n == 0).then(|| self.clone()),
+        }
+    }
+}
+
+impl Deref for StmtRef {
+    type Target = Statement;
+
+    fn deref(&self) -> &Statement {
+        match self {
+            Self::Whole(stmt) => stmt,
+            Self::Nth(stmts, i) => &stmts[*i],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Frame {
+    /// Runs statements one after the other.
+    Sequence {
+        body: StmtRef,
+        next: usize,
+    },
+    /// The loops remember whether they have just run their body, since the
+    /// thread yields after every iteration.
+    Repeat {
+        body: Rc<Statement>,
+        remaining: u64,
+        iterated: bool,
+    },
+    Forever {
+        body: Rc<Statement>,
+        iterated: bool,
+    },
+    /// Runs the body of the `repeat until` or `while` loop `stmt` for as long
+    /// as its condition doesn't evaluate to `until`.
+    Conditional {
+        stmt: StmtRef,
+        until: bool,
+        iterated: bool,
+    },
+    For {
+        counter_id: EcoString,
+        body: Rc<Statement>,
+        next: u64,
+        last: u64,
+        iterated: bool,
+    },
+    /// Marks where a custom procedure was called. Leaving it pops the
+    /// procedure's arguments. `warp` is whether the procedure runs without
+    /// screen refresh, which is also the case when it was called from one
+    /// that does.
+    Return {
+        arg_names: Vec<EcoString>,
+        warp: bool,
+    },
+    /// Sleeps until the clock reaches `until`. Like in Scratch, the thread
+    /// always yields at least once, even if the wait is already over, which
+    /// `yielded` keeps track of.
+    Wait {
+        until: f64,
+        yielded: bool,
+    },
+    /// Waits for its turn to ask `question`, then for the answer. `turn` is
+    /// what the VM's queue of asks refers to, and `asked` is whether the
+    /// question has been shown.
+    Ask {
+        question: Value,
+        turn: Rc<()>,
+        asked: bool,
+    },
+    /// Waits until the condition of the `wait until` block `stmt` is true.
+    WaitUntil {
+        stmt: StmtRef,
+    },
+    /// Moves the sprite in a straight line from `from` to `to`, starting at
+    /// the clock time `start`.
+    Glide {
+        from: (f64, f64),
+        to: (f64, f64),
+        start: f64,
+        duration: f64,
+    },
+    /// Waits for the threads started by a "broadcast and wait" block.
+    WaitForThreads {
+        ids: Vec<u64>,
+    },
+}
+
+impl Frame {
+    /// A frame that runs `body`, which may be a sequence of statements.
+    pub const fn sequence(body: StmtRef) -> Self {
+        Self::Sequence { body, next: 0 }
+    }
+
+    /// Clears the flag that loops set when they run their body and returns
+    /// whether it was set.
+    pub fn take_iterated(&mut self) -> bool {
+        match self {
+            Self::Repeat { iterated, .. }
+            | Self::Forever { iterated, .. }
+            | Self::Conditional { iterated, .. }
+            | Self::For { iterated, .. } => mem::take(iterated),
+            _ => false,
+        }
+    }
+}
+
+/// Whether the innermost custom procedure that `frames` are inside of runs
+/// without screen refresh.
+pub fn in_warp(frames: &[Frame]) -> bool {
+    frames.iter().rev().find_map(|frame| match frame {
+        Frame::Return { warp, .. } => Some(*warp),
+        _ => None,
+    }) == Some(true)
+}
+
+/// What a thread did when it was stepped.
+#[derive(Debug, Clone, Copy)]
+pub enum Status {
+    Done,
+    Yielded,
+    /// The thread has nothing to do until the clock reaches the given time,
+    /// which is infinity if only other threads finishing can wake it up.
+    Sleeping(f64),
+}
+
+/// Keeps track of every thread that is running.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    /// The threads that are stepped in turn.
+    pub threads: Vec<Thread>,
+    /// Threads started since the scheduler last went through every thread.
+    /// They are stepped from the next round on.
+    pub started: Vec<Thread>,
+    running: HashSet<u64>,
+    next_id: u64,
+    /// How many threads have finished or been stopped.
+    finished: usize,
+}
+
+impl Scheduler {
+    /// Starts a thread that runs `script` on `sprite` and returns its ID.
+    pub fn start(&mut self, sprite: Rc<Sprite>, script: &Rc<Statement>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.running.insert(id);
+        self.started.push(Thread::new(id, sprite, script));
+        id
+    }
+
+    /// Like `start`, but if a thread is already running `script` on `sprite`,
+    /// that thread is restarted and its ID is returned instead.
+    pub fn restart(
+        &mut self,
+        sprite: Rc<Sprite>,
+        script: &Rc<Statement>,
+    ) -> u64 {
+        let existing = self
+            .threads
+            .iter_mut()
+            .chain(&mut self.started)
+            .find(|thread| thread.runs(&sprite, script));
+        match existing {
+            Some(thread) => {
+                thread.restart();
+                thread.id
+            }
+            None => self.start(sprite, script),
+        }
+    }
+
+    /// Like `start`, but if a thread is already running `script` on `sprite`,
+    /// that thread carries on and its ID is returned instead.
+    pub fn start_once(
+        &mut self,
+        sprite: Rc<Sprite>,
+        script: &Rc<Statement>,
+    ) -> u64 {
+        let existing = self
+            .threads
+            .iter()
+            .chain(&self.started)
+            .find(|thread| thread.runs(&sprite, script))
+            .map(|thread| thread.id);
+        existing.unwrap_or_else(|| self.start(sprite, script))
+    }
+
+    /// Forgets the threads that have finished.
+    pub fn remove_finished(&mut self) {
+        self.threads.retain(|thread| {
+            let finished = thread.frames.is_empty();
+            if finished {
+                self.running.remove(&thread.id);
+                self.finished += 1;
+            }
+            !finished
+        });
+    }
+
+    /// Stops every thread, including those that haven't had a turn yet.
+    pub fn stop_all(&mut self) {
+        self.finished += self.threads.len() + self.started.len();
+        self.threads.clear();
+        self.started.clear();
+        self.running.clear();
+    }
+
+    /// Stops every thread that runs on `sprite`, except for the one that is
+    /// being stepped, whose frames are taken out while it runs.
+    pub fn stop_others(&mut self, sprite: &Rc<Sprite>) {
+        for thread in self.threads.iter_mut().chain(&mut self.started) {
+            if Rc::ptr_eq(&thread.sprite, sprite) {
+                thread.frames.clear();
+            }
+        }
+    }
+
+    pub fn is_running(&self, id: u64) -> bool {
+        self.running.contains(&id)
+    }
+
+    /// Describes every thread that hasn't finished, in the order that they
+    /// get their turns.
+    pub fn pending(&self) -> Vec<ThreadInfo> {
+        self.threads
+            .iter()
+            .chain(&self.started)
+            .map(Thread::info)
+            .collect()
+    }
+
+    pub const fn finished(&self) -> usize {
+        self.finished
+    }
+}
@@ -0,0 +1,23 @@
+use sb3_stuff::Value;
+
+/// Shorthands for constructing values, since `Value` comes from another
+/// crate and can't have inherent constructors here.
+pub trait ValueExt {
+    fn num(n: f64) -> Self;
+    fn str(s: &str) -> Self;
+    fn bool(b: bool) -> Self;
+}
+
+impl ValueExt for Value {
+    fn num(n: f64) -> Self {
+        Self::Num(n)
+    }
+
+    fn str(s: &str) -> Self {
+        Self::String(s.into())
+    }
+
+    fn bool(b: bool) -> Self {
+        Self::Bool(b)
+    }
+}
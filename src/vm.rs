@@ -1,38 +1,209 @@
-use crate::{expr::Expr, sprite::Sprite, statement::Statement};
+use crate::{
+    clock::{Clock, SystemClock},
+    deser::DeError,
+    expr::Expr,
+    input::{Answers, InputSource, NoInput},
+    output::{Capture, Output},
+    snapshot::Snapshot,
+    sprite::{DeSprite, Sprite},
+    statement::Statement,
+    thread::{in_warp, Frame, Scheduler, Status, StmtRef, ThreadInfo},
+};
 use ecow::EcoString;
-use sb3_stuff::{Index, Value};
-use serde::Deserialize;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use sb3_stuff::Value;
+use serde::{de::Error as _, Deserialize, Deserializer};
 use std::{
-    cell::{Cell, RefCell},
+    cell::{Cell, OnceCell, RefCell, RefMut},
     cmp,
-    collections::HashMap,
-    io::Write,
-    ops, time,
+    collections::{BTreeSet, HashMap, VecDeque},
+    fs,
+    io::{BufRead, BufReader, Read, Seek, Write},
+    mem, ops,
+    path::Path,
+    ptr,
+    rc::{Rc, Weak},
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread, time,
 };
 use thiserror::Error;
+use zip::ZipArchive;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 pub struct VM {
-    #[serde(rename = "targets")]
-    #[serde(deserialize_with = "crate::sprite::deserialize_sprites")]
-    sprites: HashMap<EcoString, Sprite>,
-    #[serde(skip_deserializing)]
-    // FIXME: this should be deserialized from the sprites
+    sprites: HashMap<EcoString, Rc<Sprite>>,
+    clones: RefCell<Vec<Rc<Sprite>>>,
     vars: RefCell<HashMap<EcoString, Value>>,
-    #[serde(skip_deserializing)]
-    // FIXME: this should be deserialized from the sprites
     lists: RefCell<HashMap<EcoString, Vec<Value>>>,
-    #[serde(skip_deserializing)]
     proc_args: RefCell<HashMap<EcoString, Vec<Value>>>,
-    #[serde(skip_deserializing)]
     answer: RefCell<String>,
-    #[serde(skip_deserializing)]
-    #[serde(default = "default_timer")]
-    timer: Cell<time::Instant>,
+    /// The `ask` blocks that are waiting for their turn, oldest first. Each
+    /// one is dropped by its `Frame::Ask` when it is answered or stopped.
+    asks: RefCell<VecDeque<Weak<()>>>,
+    /// Created when first used, since the system clock isn't available
+    /// everywhere, such as on WebAssembly.
+    clock: OnceCell<Box<dyn Clock>>,
+    /// The clock's time when the timer was last reset.
+    timer: Cell<f64>,
+    tempo: Cell<f64>,
+    input_source: Box<dyn InputSource>,
+    output: RefCell<Output>,
+    answers: RefCell<Answers>,
+    max_string_len: Option<usize>,
+    fencing: bool,
+    echo_answers: bool,
+    timed_out: Arc<AtomicBool>,
+    stop_requested: Arc<AtomicBool>,
+    variable_names: Vec<(EcoString, EcoString)>,
+    list_names: Vec<(EcoString, EcoString)>,
+    /// Seeded from the operating system when first used unless a seed has
+    /// been given, for the same reason as `clock`.
+    rng: RefCell<Option<SmallRng>>,
+    /// The threads that have been started but haven't finished yet, which
+    /// outlive a single call to `step`.
+    scheduler: RefCell<Scheduler>,
+    /// Events that haven't been handled yet, oldest first.
+    events: RefCell<VecDeque<Event>>,
+}
+
+impl<'de> Deserialize<'de> for VM {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Project<'a> {
+            #[serde(borrow)]
+            targets: Vec<DeSprite<'a>>,
+        }
+
+        let Project { targets } = Project::deserialize(deserializer)?;
+
+        // Only the stage has a tempo.
+        let tempo = targets
+            .iter()
+            .find_map(|target| target.tempo)
+            .unwrap_or(DEFAULT_TEMPO);
+
+        let variable_names = targets
+            .iter()
+            .flat_map(|target| &target.variables)
+            .map(|(id, var)| (id.clone(), var.name.clone()))
+            .collect();
+        let list_names = targets
+            .iter()
+            .flat_map(|target| &target.lists)
+            .map(|(id, list)| (id.clone(), list.name.clone()))
+            .collect();
+
+        // Sprites keep their local variables and lists to themselves, so
+        // only those of the stage are global.
+        let stage = targets.iter().find(|target| target.is_stage);
+        let vars = stage.map_or_else(HashMap::new, DeSprite::initial_vars);
+        let lists = stage.map_or_else(HashMap::new, DeSprite::initial_lists);
+
+        let mut sprites = HashMap::with_capacity(targets.len());
+        for target in targets {
+            // Scratch keeps target names unique, so a duplicate means that
+            // the project is corrupt and one of them would get lost.
+            if sprites.contains_key(&target.name) {
+                return Err(D::Error::custom(DeError::DuplicateTarget(
+                    target.name.to_string(),
+                )));
+            }
+            let name = target.name.clone();
+            let sprite = target.build().map_err(D::Error::custom)?;
+            sprites.insert(name, Rc::new(sprite));
+        }
+
+        Ok(Self {
+            sprites,
+            clones: RefCell::default(),
+            vars: RefCell::new(vars),
+            lists: RefCell::new(lists),
+            proc_args: RefCell::default(),
+            answer: RefCell::default(),
+            asks: RefCell::default(),
+            clock: OnceCell::new(),
+            timer: Cell::new(0.0),
+            tempo: Cell::new(tempo),
+            input_source: Box::new(NoInput),
+            // Standard output is line buffered, so whole lines show up as
+            // soon as they are printed without flushing after every `print`.
+            output: RefCell::new(Output(Box::new(std::io::stdout()))),
+            answers: RefCell::new(Answers(Box::new(BufReader::new(
+                std::io::stdin(),
+            )))),
+            max_string_len: None,
+            fencing: false,
+            echo_answers: false,
+            timed_out: Arc::default(),
+            stop_requested: Arc::default(),
+            variable_names,
+            list_names,
+            rng: RefCell::new(None),
+            scheduler: RefCell::default(),
+            events: RefCell::default(),
+        })
+    }
+}
+
+const STAGE_WIDTH: f64 = 480.0;
+const STAGE_HEIGHT: f64 = 360.0;
+
+/// Scratch refuses to create more clones than this at once.
+const MAX_CLONES: usize = 300;
+
+/// How long a frame lasts in Scratch, which runs at 30 frames per second.
+const FRAME_SECS: f64 = 1.0 / 30.0;
+
+/// The smallest and largest sizes in percent that sprites can have. Scratch
+/// works these out from the dimensions of the costume, which aren't known
+/// here, so these are its limits for a costume of 100 by 100 pixels.
+const MIN_SIZE: f64 = 5.0;
+const MAX_SIZE: f64 = 540.0;
+
+/// The tempo in beats per minute used when the project doesn't specify one.
+const DEFAULT_TEMPO: f64 = 60.0;
+
+/// Stops a running VM from another thread, like pressing the stop button.
+#[derive(Debug, Clone)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    pub fn request_stop(&self) {
+        self.0.store(true, Relaxed);
+    }
+}
+
+/// What a list index or letter number refers to, as worked out by
+/// `VM::resolve_index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexResolution {
+    /// The item at the given position, counting from 0, which is always in
+    /// range.
+    Nth(usize),
+    /// Every item, which only "delete of" accepts.
+    All,
+    /// The index is out of range or doesn't refer to any item.
+    Invalid,
 }
 
-fn default_timer() -> Cell<time::Instant> {
-    Cell::new(time::Instant::now())
+/// Something that happens outside of the project and starts the scripts
+/// whose hats respond to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    GreenFlag,
+    /// A key was pressed, named like in "when key pressed" hats, such as
+    /// "space" or "a".
+    KeyPressed(String),
+    /// The sprite with the given name, or the stage, was clicked.
+    Clicked(String),
+    Broadcast(String),
 }
 
 #[derive(Debug, Error)]
@@ -43,52 +214,754 @@ pub enum VMError {
     StopAll,
     #[error("unknown opcode: `{0}`")]
     UnknownOpcode(String),
+    #[error("unknown math operator: `{0}`")]
+    UnknownMathOp(EcoString),
+    #[error("custom block `{proccode}` has no argument with ID `{id}`")]
+    UnknownArgument { proccode: String, id: EcoString },
+    #[error("missing input `{0}`")]
+    MissingInput(String),
+    #[error("no sprite is named `{0}`")]
+    UnknownSprite(String),
+    #[error("timed out")]
+    Timeout,
+    #[error("still running after {0} steps")]
+    StepLimit(usize),
+    #[error("string would exceed the maximum length of {0} bytes")]
+    StringTooLong(usize),
     #[error("IO error: {0}")]
     IOError(#[from] std::io::Error),
 }
 
 type VMResult<T> = Result<T, VMError>;
 
+/// An error that prevents a project from being loaded.
+#[derive(Debug, Error)]
+pub enum LoadError {
+    #[error("IO error: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("Zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Deserialization error: {0}")]
+    Deserialization(#[from] serde_json::Error),
+}
+
+/// The outcome of `VM::run_headless_collect`.
+#[derive(Debug)]
+pub struct RunReport {
+    /// Everything the project printed.
+    pub output: String,
+    /// The contents of every variable and list after the run.
+    pub state: Snapshot,
+    pub error: Option<VMError>,
+}
+
 impl VM {
+    /// Loads a project from an `.sb3` file.
+    pub fn from_reader(reader: impl Read + Seek) -> Result<Self, LoadError> {
+        let mut archive = ZipArchive::new(reader)?;
+        let project_json = archive.by_name("project.json")?;
+        Ok(serde_json::from_reader(project_json)?)
+    }
+
+    /// Loads a project that has been extracted into the directory `dir`.
+    pub fn from_project_dir(dir: &Path) -> Result<Self, LoadError> {
+        let project_json = fs::read(dir.join("project.json"))?;
+        Ok(serde_json::from_slice(&project_json)?)
+    }
+
+    /// Replaces the source that the sensing blocks read the keyboard, mouse
+    /// and microphone from.
+    pub fn set_input_source(&mut self, source: Box<dyn InputSource>) {
+        self.input_source = source;
+    }
+
+    /// Replaces where the project's output is written, which is standard
+    /// output by default.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        *self.output.get_mut() = Output(output);
+    }
+
+    /// Replaces where answers to "ask and wait" blocks are read from, which
+    /// is standard input by default.
+    pub fn set_answers(&mut self, answers: Box<dyn BufRead>) {
+        *self.answers.get_mut() = Answers(answers);
+    }
+
+    /// Limits how long strings built by the VM may grow, which protects
+    /// against projects that exhaust memory by joining strings in a loop.
+    /// Scratch has no such limit, so there is none by default.
+    pub const fn set_max_string_len(&mut self, max_string_len: Option<usize>) {
+        self.max_string_len = max_string_len;
+    }
+
+    /// Keeps sprites from moving off the stage. Scratch lets sprites leave
+    /// the stage by default, so fencing is off unless enabled.
+    pub const fn set_fencing(&mut self, fencing: bool) {
+        self.fencing = fencing;
+    }
+
+    /// Writes the answers given to "ask and wait" blocks to the output. A
+    /// terminal already echoes what the user types, but output captured
+    /// elsewhere would otherwise only contain the questions.
+    pub const fn set_echo_answers(&mut self, echo_answers: bool) {
+        self.echo_answers = echo_answers;
+    }
+
+    /// Seeds the random number generator so that the project's random
+    /// choices can be reproduced.
+    #[must_use]
+    pub fn with_rng_seed(mut self, seed: u64) -> Self {
+        self.rng = RefCell::new(Some(SmallRng::seed_from_u64(seed)));
+        self
+    }
+
+    /// Replaces the clock used for waiting and for the timer, which is reset
+    /// in the process.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.timer.set(clock.now());
+        self.clock = OnceCell::from(clock);
+    }
+
     pub fn run(&self) -> VMResult<()> {
-        // This should be a `try` block
-        let res = (|| {
-            for spr in self.sprites.values() {
-                for proc in &spr.procs.when_flag_clicked {
-                    self.run_proc(spr, proc)?;
-                }
+        self.green_flag();
+        let mut sched = self.scheduler.borrow_mut();
+        let res = self.run_scheduler(&mut sched);
+        self.finish_run(&mut sched, res)
+    }
+
+    /// Clicks the green flag, which stops every script, deletes any clones
+    /// left from a previous run and starts the green flag scripts. They don't
+    /// run until `step` is called.
+    pub fn green_flag(&self) {
+        self.start_green_flag(&mut self.scheduler.borrow_mut());
+    }
+
+    /// Queues `event` to be handled at the start of the next step, or of the
+    /// next run.
+    pub fn push_event(&self, event: Event) {
+        self.events.borrow_mut().push_back(event);
+    }
+
+    /// Gives every thread one turn, which lets the host application run the
+    /// project a frame at a time instead of to completion. Sleeps until the
+    /// first thread wakes up if all of them are waiting. Returns whether any
+    /// threads are left to run.
+    pub fn step(&self) -> VMResult<bool> {
+        let mut sched = self.scheduler.borrow_mut();
+        match self.tick(&mut sched) {
+            Ok(true) => Ok(true),
+            res => self.finish_run(&mut sched, res.map(drop)).map(|()| false),
+        }
+    }
+
+    /// Calls `step` until every thread has finished, but gives up with
+    /// `VMError::StepLimit` after `max_steps` steps, which lets tests run
+    /// projects to completion without hanging on ones that never finish.
+    pub fn step_until_idle(&self, max_steps: usize) -> VMResult<()> {
+        for _ in 0..max_steps {
+            if !self.step()? {
+                return Ok(());
+            }
+        }
+        let mut sched = self.scheduler.borrow_mut();
+        self.finish_run(&mut sched, Err(VMError::StepLimit(max_steps)))
+    }
+
+    /// Sends the broadcast `name` to the project, which lets the host
+    /// application trigger scripts. Like any other event, the receiving
+    /// scripts are started at the next step and run alongside the scripts
+    /// that are already running.
+    pub fn broadcast(&self, name: &str) {
+        self.push_event(Event::Broadcast(name.to_owned()));
+    }
+
+    /// Describes every thread that has been started but hasn't finished.
+    pub fn pending_threads(&self) -> Vec<ThreadInfo> {
+        self.scheduler.borrow().pending()
+    }
+
+    /// Counts the threads that have finished or been stopped since the VM was
+    /// loaded.
+    pub fn finished_threads(&self) -> usize {
+        self.scheduler.borrow().finished()
+    }
+
+    /// Makes every running script stop at its next loop iteration.
+    pub fn request_stop(&self) {
+        self.stop_requested.store(true, Relaxed);
+    }
+
+    /// Returns a handle that can request a stop from another thread while
+    /// the VM is running.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle(Arc::clone(&self.stop_requested))
+    }
+
+    /// Returns the ID and name of every variable in the project.
+    pub fn variable_names(&self) -> Vec<(EcoString, EcoString)> {
+        self.variable_names.clone()
+    }
+
+    /// Returns the ID and name of every list in the project.
+    pub fn list_names(&self) -> Vec<(EcoString, EcoString)> {
+        self.list_names.clone()
+    }
+
+    /// Runs the project with its output captured in memory instead of
+    /// printed, and reports everything about the run in one go. Questions
+    /// asked during the run get empty answers instead of reading from
+    /// standard input.
+    pub fn run_headless_collect(&self) -> RunReport {
+        let capture = Capture::default();
+        let old_output = self.output.replace(Output(Box::new(capture.clone())));
+        let old_answers =
+            self.answers.replace(Answers(Box::new(std::io::empty())));
+        let error = self.run().err();
+        self.output.replace(old_output);
+        self.answers.replace(old_answers);
+
+        RunReport {
+            output: capture.contents(),
+            state: self.snapshot(),
+            error,
+        }
+    }
+
+    /// Returns a copy of the current items of the list named `name`. If
+    /// several sprites have a local list with that name, any one of them may
+    /// be returned.
+    pub fn list_snapshot(&self, name: &str) -> Option<Vec<Value>> {
+        let (id, _) = self
+            .list_names
+            .iter()
+            .find(|(_, list_name)| list_name == name)?;
+        self.lists.borrow().get(id).cloned().or_else(|| {
+            self.sprites
+                .values()
+                .find_map(|sprite| sprite.lists.borrow().get(id).cloned())
+        })
+    }
+
+    /// Returns the position of the sprite named `sprite_name`.
+    pub fn position_of(&self, sprite_name: &str) -> Option<(f64, f64)> {
+        let sprite = self.sprites.get(sprite_name)?;
+        Some((sprite.x.get(), sprite.y.get()))
+    }
+
+    /// Returns the direction of the sprite named `sprite_name` in degrees.
+    pub fn direction_of(&self, sprite_name: &str) -> Option<f64> {
+        Some(self.sprites.get(sprite_name)?.direction.get())
+    }
+
+    /// Returns the size of the sprite named `sprite_name` in percent.
+    pub fn size_of(&self, sprite_name: &str) -> Option<f64> {
+        Some(self.sprites.get(sprite_name)?.size.get())
+    }
+
+    /// Returns the name of the current costume of the sprite named
+    /// `sprite_name`.
+    pub fn costume_of(&self, sprite_name: &str) -> Option<&str> {
+        let sprite = self.sprites.get(sprite_name)?;
+        sprite
+            .costumes
+            .get(sprite.costume.get())
+            .map(EcoString::as_str)
+    }
+
+    /// Returns the number of the current costume of the sprite named
+    /// `sprite_name`, counting from 1.
+    pub fn costume_number_of(&self, sprite_name: &str) -> Option<usize> {
+        Some(self.sprites.get(sprite_name)?.costume.get() + 1)
+    }
+
+    /// Returns whether the sprite named `sprite_name` is shown.
+    pub fn is_visible(&self, sprite_name: &str) -> Option<bool> {
+        Some(self.sprites.get(sprite_name)?.visible.get())
+    }
+
+    /// Evaluates a reporter as if it were used by the sprite named
+    /// `sprite_name`, which lets tools inspect the project's current state.
+    pub fn eval_reporter(
+        &self,
+        sprite_name: &str,
+        expr: &Expr,
+    ) -> VMResult<Value> {
+        let sprite = self
+            .sprites
+            .get(sprite_name)
+            .ok_or_else(|| VMError::UnknownSprite(sprite_name.to_owned()))?;
+        self.eval_expr(sprite, expr)
+    }
+
+    /// Captures the current contents of every variable and list, including
+    /// the local ones of each sprite but not those of clones.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut vars = self.vars.borrow().clone();
+        let mut lists = self.lists.borrow().clone();
+        for sprite in self.sprites.values() {
+            vars.extend(sprite.vars.borrow().clone());
+            lists.extend(sprite.lists.borrow().clone());
+        }
+        Snapshot { vars, lists }
+    }
+
+    /// Runs the project like `run`, but gives up with `VMError::Timeout` once
+    /// `timeout` has passed. This also stops projects that are stuck in a
+    /// loop which never finishes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the thread that enforces the timeout panics, which it never
+    /// does.
+    pub fn run_with_timeout(&self, timeout: time::Duration) -> VMResult<()> {
+        let (finished, watchdog_finished) = mpsc::channel::<()>();
+        let timed_out = Arc::clone(&self.timed_out);
+        let watchdog = thread::spawn(move || {
+            if watchdog_finished.recv_timeout(timeout)
+                == Err(RecvTimeoutError::Timeout)
+            {
+                timed_out.store(true, Relaxed);
             }
+        });
+
+        let res = self.run();
+        // Wakes up the watchdog so that it doesn't outlive the run, and waits
+        // for it so that it can't time out whatever runs next.
+        drop(finished);
+        watchdog.join().expect("watchdog thread panicked");
+        // The timeout has been handled, so the next run starts afresh.
+        self.timed_out.store(false, Relaxed);
+        res
+    }
+
+    /// Checks that every block in the project is one the VM knows how to run,
+    /// so that unsupported projects can be rejected before running.
+    pub fn check_opcodes(&self) -> Result<(), DeError> {
+        self.unsupported_opcodes()
+            .into_iter()
+            .next()
+            .map_or(Ok(()), |opcode| Err(DeError::UnsupportedOpcode(opcode)))
+    }
+
+    /// Renders the scripts of every sprite as pseudocode, sorted by sprite
+    /// name.
+    pub fn dump_ir(&self) -> String {
+        let mut sprites = self.sprites.iter().collect::<Vec<_>>();
+        sprites.sort_unstable_by_key(|&(name, _)| name);
+        sprites
+            .into_iter()
+            .map(|(name, sprite)| format!("// {name}\n{}\n", sprite.procs))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Returns every statement and reporter opcode used in the project that
+    /// the VM doesn't know how to run.
+    pub fn unsupported_opcodes(&self) -> BTreeSet<String> {
+        let mut unsupported = BTreeSet::new();
+        let scripts = self.sprites.values().flat_map(|spr| spr.procs.scripts());
+        for script in scripts {
+            script.walk(&mut |stmt| {
+                if let Statement::Regular { opcode, .. } = stmt {
+                    if !is_builtin_statement(opcode) {
+                        unsupported.insert(opcode.to_string());
+                    }
+                }
+                for expr in stmt.exprs() {
+                    expr.walk(&mut |expr| match expr {
+                        Expr::Call { opcode, .. }
+                            if !is_builtin_reporter(opcode) =>
+                        {
+                            unsupported.insert(opcode.clone());
+                        }
+                        Expr::MathOp { op, .. } if mathop(op).is_none() => {
+                            unsupported.insert(format!("operator_mathop {op}"));
+                        }
+                        _ => {}
+                    });
+                }
+            });
+        }
+        unsupported
+    }
+
+    /// Counts the `forever` loops with empty bodies, which spin without ever
+    /// making progress and are almost always a mistake.
+    pub fn count_empty_forever_loops(&self) -> usize {
+        let mut count = 0;
+        let scripts = self.sprites.values().flat_map(|spr| spr.procs.scripts());
+        for script in scripts {
+            script.walk(&mut |stmt| {
+                if let Statement::Forever { body } = stmt {
+                    count += usize::from(body.is_empty());
+                }
+            });
+        }
+        count
+    }
+
+    /// Called at every loop iteration so that long-running scripts can be
+    /// interrupted from outside the VM.
+    fn check_interrupt(&self) -> VMResult<()> {
+        if self.timed_out.load(Relaxed) {
+            Err(VMError::Timeout)
+        } else if self.stop_requested.load(Relaxed) {
+            Err(VMError::StopAll)
+        } else {
             Ok(())
-        })();
+        }
+    }
+
+    /// Runs the threads of `sched`, and any threads that they start, until
+    /// all of them have finished.
+    fn run_scheduler(&self, sched: &mut Scheduler) -> VMResult<()> {
+        while self.tick(sched)? {}
+        Ok(())
+    }
 
+    /// Handles the queued events, then gives every thread of `sched` one turn
+    /// and returns whether any threads are left.
+    fn tick(&self, sched: &mut Scheduler) -> VMResult<bool> {
+        for event in self.events.take() {
+            self.handle_event(sched, &event);
+        }
+        sched.threads.append(&mut sched.started);
+        if sched.threads.is_empty() {
+            return Ok(false);
+        }
+        self.check_interrupt()?;
+
+        // The earliest time that a thread is waiting for, as long as every
+        // thread is waiting.
+        let mut wake_at = Some(f64::INFINITY);
+        // Threads are stepped where they are, since any of them may be
+        // restarted by a broadcast, including those that haven't had their
+        // turn yet.
+        for index in 0..sched.threads.len() {
+            match self.step_thread(sched, index)? {
+                // Threads that are waiting for this one to finish can carry on
+                // in the next round.
+                Status::Done | Status::Yielded => wake_at = None,
+                Status::Sleeping(until) => {
+                    wake_at = wake_at.map(|wake_at| wake_at.min(until));
+                }
+            }
+        }
+        sched.remove_finished();
+
+        // Nothing can happen until the first sleeping thread wakes up.
+        let waiting = !sched.threads.is_empty() && sched.started.is_empty();
+        if let Some(wake_at) = wake_at.filter(|_| waiting) {
+            self.sleep_until(wake_at)?;
+        }
+        Ok(!sched.threads.is_empty() || !sched.started.is_empty())
+    }
+
+    /// Cleans up once the threads of `sched` have finished or the run has
+    /// failed with `res`.
+    fn finish_run(
+        &self,
+        sched: &mut Scheduler,
+        res: VMResult<()>,
+    ) -> VMResult<()> {
+        // Threads that were interrupted don't carry on in the next run.
+        sched.stop_all();
+        // The stop request has been handled, so the next run starts afresh.
+        self.stop_requested.store(false, Relaxed);
+
+        let flushed = self.output().flush();
         match res {
-            Err(VMError::StopAll) => Ok(()),
+            Err(VMError::StopAll) => {
+                self.delete_clones();
+                Ok(())
+            }
             res => res,
         }
+        .and_then(|()| flushed.map_err(VMError::from))
     }
 
-    fn run_proc(&self, sprite: &Sprite, proc: &Statement) -> VMResult<()> {
-        match self.run_statement(sprite, proc) {
-            Err(VMError::StopThisScript) => Ok(()),
-            res => res,
+    /// Sleeps until the clock reaches `until`, at most a frame at a time so
+    /// that long waits can still be stopped or time out.
+    fn sleep_until(&self, until: f64) -> VMResult<()> {
+        loop {
+            let duration = until - self.clock().now();
+            if duration <= 0.0 {
+                return Ok(());
+            }
+            // Rounding up to whole nanoseconds makes sure that the clock
+            // reaches `until`, since a remainder too small for a `Duration`
+            // would otherwise never be slept off.
+            let nanos = (duration.min(FRAME_SECS) * 1e9).ceil();
+            self.clock().sleep(time::Duration::from_nanos(nanos as u64));
+            self.check_interrupt()?;
+        }
+    }
+
+    /// Runs the thread at `index` in `sched` until it yields or finishes.
+    fn step_thread(
+        &self,
+        sched: &mut Scheduler,
+        index: usize,
+    ) -> VMResult<Status> {
+        let thread = &mut sched.threads[index];
+        // Threads of deleted clones stop without running any further.
+        if thread.sprite.deleted.get() {
+            thread.frames.clear();
+            return Ok(Status::Done);
+        }
+        // The frames are taken out of the thread while it runs, which leaves
+        // the scheduler free for the thread to start and restart others.
+        let sprite = Rc::clone(&thread.sprite);
+        let mut frames = mem::take(&mut thread.frames);
+        // Reporters look up procedure arguments in the VM, so the thread's own
+        // arguments are swapped in while it runs.
+        mem::swap(&mut *self.proc_args.borrow_mut(), &mut thread.proc_args);
+        let status = self.step_frames(sched, &sprite, &mut frames);
+        let thread = &mut sched.threads[index];
+        mem::swap(&mut *self.proc_args.borrow_mut(), &mut thread.proc_args);
+        if thread.frames.is_empty() {
+            thread.frames = frames;
+            status
+        } else {
+            // The thread broadcast a message that restarted itself, so it
+            // starts over from the top on its next turn.
+            thread.proc_args.clear();
+            status.map(|_| Status::Yielded)
         }
     }
 
-    fn run_statement(&self, sprite: &Sprite, stmt: &Statement) -> VMResult<()> {
-        match stmt {
-            Statement::Regular { opcode, inputs } => {
-                self.call_builtin_statement(sprite, opcode, inputs)
+    fn step_frames(
+        &self,
+        sched: &mut Scheduler,
+        sprite: &Rc<Sprite>,
+        frames: &mut Vec<Frame>,
+    ) -> VMResult<Status> {
+        loop {
+            let Some(frame) = frames.last_mut() else {
+                return Ok(Status::Done);
+            };
+            // Loops yield after every iteration, except in procedures that
+            // run without screen refresh.
+            if frame.take_iterated() {
+                if in_warp(frames) {
+                    continue;
+                }
+                return Ok(Status::Yielded);
+            }
+            match frame {
+                Frame::Sequence { body, next } => {
+                    let Some(stmt) = body.nth(*next) else {
+                        frames.pop();
+                        continue;
+                    };
+                    *next += 1;
+                    match self.start_statement(sched, sprite, frames, &stmt) {
+                        Err(VMError::StopThisScript) => {
+                            self.stop_this_script(frames);
+                        }
+                        res => res?,
+                    }
+                }
+                Frame::Repeat {
+                    body,
+                    remaining,
+                    iterated,
+                } => {
+                    if *remaining == 0 {
+                        frames.pop();
+                        continue;
+                    }
+                    self.check_interrupt()?;
+                    *remaining -= 1;
+                    *iterated = true;
+                    let body = StmtRef::Whole(Rc::clone(body));
+                    frames.push(Frame::sequence(body));
+                }
+                Frame::Forever { body, iterated } => {
+                    self.check_interrupt()?;
+                    *iterated = true;
+                    let body = StmtRef::Whole(Rc::clone(body));
+                    frames.push(Frame::sequence(body));
+                }
+                Frame::Conditional {
+                    stmt,
+                    until,
+                    iterated,
+                } => {
+                    *iterated = true;
+                    let (stmt, until) = (stmt.clone(), *until);
+                    let (Statement::Until { condition, body }
+                    | Statement::While { condition, body }) = &*stmt
+                    else {
+                        unreachable!("conditional loop frame for a non-loop");
+                    };
+                    if self.eval_expr(sprite, condition)?.to_bool() == until {
+                        frames.pop();
+                        continue;
+                    }
+                    self.check_interrupt()?;
+                    let body = StmtRef::Whole(Rc::clone(body));
+                    frames.push(Frame::sequence(body));
+                }
+                Frame::For {
+                    counter_id,
+                    body,
+                    next,
+                    last,
+                    iterated,
+                } => {
+                    if *next > *last {
+                        frames.pop();
+                        continue;
+                    }
+                    self.check_interrupt()?;
+                    let counter = Value::Num(*next as f64);
+                    self.vars_for(sprite, counter_id)
+                        .borrow_mut()
+                        .insert(counter_id.clone(), counter);
+                    *next += 1;
+                    *iterated = true;
+                    let body = StmtRef::Whole(Rc::clone(body));
+                    frames.push(Frame::sequence(body));
+                }
+                Frame::Return { arg_names, .. } => {
+                    let arg_names = mem::take(arg_names);
+                    frames.pop();
+                    self.pop_proc_args(&arg_names);
+                }
+                Frame::Wait { until, yielded } => {
+                    if !*yielded {
+                        *yielded = true;
+                        return Ok(Status::Yielded);
+                    }
+                    if self.clock().now() < *until {
+                        return Ok(Status::Sleeping(*until));
+                    }
+                    frames.pop();
+                }
+                Frame::Ask {
+                    question,
+                    turn,
+                    asked,
+                } => {
+                    // Asks are answered one at a time in the order that they
+                    // were started, like in Scratch.
+                    if !self.is_turn_to_ask(turn) {
+                        return Ok(Status::Yielded);
+                    }
+                    // The question is shown for at least a frame before the
+                    // answer is read, so other scripts get to run first.
+                    if !*asked {
+                        self.show_question(question)?;
+                        *asked = true;
+                        return Ok(Status::Yielded);
+                    }
+                    self.read_answer()?;
+                    self.asks.borrow_mut().pop_front();
+                    frames.pop();
+                }
+                Frame::WaitUntil { stmt } => {
+                    let Statement::Regular { inputs, .. } = &**stmt else {
+                        unreachable!("`wait until` frame for another block");
+                    };
+                    // An empty condition counts as false like in Scratch, so
+                    // the thread waits until it is stopped.
+                    let done = match inputs.get("CONDITION") {
+                        Some(condition) => {
+                            self.eval_expr(sprite, condition)?.to_bool()
+                        }
+                        None => false,
+                    };
+                    if !done {
+                        self.check_interrupt()?;
+                        // Checking again every frame like Scratch does lets
+                        // conditions that depend on the timer become true
+                        // while every thread is waiting.
+                        return Ok(Status::Sleeping(
+                            self.clock().now() + FRAME_SECS,
+                        ));
+                    }
+                    frames.pop();
+                }
+                Frame::Glide {
+                    from,
+                    to,
+                    start,
+                    duration,
+                } => {
+                    let now = self.clock().now();
+                    let end = *start + *duration;
+                    if now >= end {
+                        self.set_position(sprite, to.0, to.1);
+                        frames.pop();
+                        continue;
+                    }
+                    let t = (now - *start) / *duration;
+                    self.set_position(
+                        sprite,
+                        (to.0 - from.0).mul_add(t, from.0),
+                        (to.1 - from.1).mul_add(t, from.1),
+                    );
+                    // The sprite moves once per frame like in Scratch.
+                    return Ok(Status::Sleeping((now + FRAME_SECS).min(end)));
+                }
+                Frame::WaitForThreads { ids } => {
+                    if ids.iter().any(|&id| sched.is_running(id)) {
+                        // Only the threads being waited for can end this wait,
+                        // so this thread doesn't keep the scheduler awake.
+                        return Ok(Status::Sleeping(f64::INFINITY));
+                    }
+                    frames.pop();
+                }
+            }
+        }
+    }
+
+    /// Returns from the innermost custom procedure like Scratch does, or ends
+    /// the thread if it isn't inside of one.
+    fn stop_this_script(&self, frames: &mut Vec<Frame>) {
+        while let Some(frame) = frames.pop() {
+            if let Frame::Return { arg_names, .. } = frame {
+                self.pop_proc_args(&arg_names);
+                return;
+            }
+        }
+    }
+
+    fn pop_proc_args(&self, arg_names: &[EcoString]) {
+        let mut proc_args = self.proc_args.borrow_mut();
+        for name in arg_names {
+            if let Some(stack) = proc_args.get_mut(name) {
+                stack.pop();
+            }
+        }
+    }
+
+    /// Starts running `stmt`. Statements that contain other statements or
+    /// take time push frames so that the thread can yield while running them.
+    fn start_statement(
+        &self,
+        sched: &mut Scheduler,
+        sprite: &Rc<Sprite>,
+        frames: &mut Vec<Frame>,
+        stmt: &StmtRef,
+    ) -> VMResult<()> {
+        match &**stmt {
+            Statement::Regular { opcode, inputs, .. } => self
+                .start_builtin_statement(
+                    sched, sprite, frames, stmt, opcode, inputs,
+                ),
+            Statement::Do(_) => {
+                frames.push(Frame::sequence(stmt.clone()));
+                Ok(())
             }
-            Statement::Do(stmts) => stmts
-                .iter()
-                .try_for_each(|stmt| self.run_statement(sprite, stmt)),
             Statement::If { condition, if_true } => {
                 let condition = self.eval_expr(sprite, condition)?.to_bool();
                 if condition {
-                    self.run_statement(sprite, if_true)
-                } else {
-                    Ok(())
+                    let if_true = StmtRef::Whole(Rc::clone(if_true));
+                    frames.push(Frame::sequence(if_true));
                 }
+                Ok(())
             }
             Statement::IfElse {
                 condition,
@@ -96,31 +969,40 @@ impl VM {
                 if_false,
             } => {
                 let condition = self.eval_expr(sprite, condition)?.to_bool();
-                self.run_statement(
-                    sprite,
-                    if condition { if_true } else { if_false },
-                )
+                let body = if condition { if_true } else { if_false };
+                frames.push(Frame::sequence(StmtRef::Whole(Rc::clone(body))));
+                Ok(())
             }
             Statement::Repeat { times, body } => {
                 let times = self.eval_expr(sprite, times)?.to_num().round();
-                for _ in 0..times as u64 {
-                    self.run_statement(sprite, body)?;
-                }
+                frames.push(Frame::Repeat {
+                    body: Rc::clone(body),
+                    remaining: times as u64,
+                    iterated: false,
+                });
                 Ok(())
             }
-            Statement::Forever { body } => loop {
-                self.run_statement(sprite, body)?;
-            },
-            Statement::Until { condition, body } => {
-                while !self.eval_expr(sprite, condition)?.to_bool() {
-                    self.run_statement(sprite, body)?;
-                }
+            Statement::Forever { body } => {
+                frames.push(Frame::Forever {
+                    body: Rc::clone(body),
+                    iterated: false,
+                });
                 Ok(())
             }
-            Statement::While { condition, body } => {
-                while self.eval_expr(sprite, condition)?.to_bool() {
-                    self.run_statement(sprite, body)?;
-                }
+            Statement::Until { .. } => {
+                frames.push(Frame::Conditional {
+                    stmt: stmt.clone(),
+                    until: true,
+                    iterated: false,
+                });
+                Ok(())
+            }
+            Statement::While { .. } => {
+                frames.push(Frame::Conditional {
+                    stmt: stmt.clone(),
+                    until: false,
+                    iterated: false,
+                });
                 Ok(())
             }
             Statement::For {
@@ -129,12 +1011,13 @@ impl VM {
                 body,
             } => {
                 let times = self.eval_expr(sprite, times)?.to_num().ceil();
-                for i in 1..=times as u64 {
-                    self.vars
-                        .borrow_mut()
-                        .insert(counter_id.clone(), Value::Num(i as f64));
-                    self.run_statement(sprite, body)?;
-                }
+                frames.push(Frame::For {
+                    counter_id: counter_id.clone(),
+                    body: Rc::clone(body),
+                    next: 1,
+                    last: times as u64,
+                    iterated: false,
+                });
                 Ok(())
             }
             Statement::ProcCall { proccode, args } => {
@@ -148,45 +1031,56 @@ impl VM {
                     "putchar %s" | "print %s" => {
                         if let Some(s) = args.values().next() {
                             let s = self.eval_expr(sprite, s)?;
-                            print!("{s}");
-                            std::io::stdout().flush()?;
+                            write!(self.output(), "{s}")?;
                         }
                     }
                     "println %s" => {
                         if let Some(s) = args.values().next() {
                             let s = self.eval_expr(sprite, s)?;
-                            println!("{s}");
+                            let mut output = self.output();
+                            writeln!(output, "{s}")?;
+                            output.flush()?;
                         }
                     }
                     "term-clear" => {
-                        println!("\x1b[2J\x1b[H");
+                        writeln!(self.output(), "\x1b[2J\x1b[H")?;
                     }
                     _ => {
-                        for (id, arg) in args {
-                            let arg = self.eval_expr(sprite, arg)?;
-                            self.proc_args
-                                .borrow_mut()
-                                .entry(
-                                    proc.arg_names_by_id
-                                        .get(id)
-                                        .unwrap()
-                                        .clone(),
-                                )
+                        // Look up every argument name before pushing anything
+                        // so that an unknown ID can't leave stale values on
+                        // the argument stacks.
+                        let arg_names = args
+                            .keys()
+                            .map(|id| {
+                                proc.arg_names_by_id
+                                    .get(id)
+                                    .cloned()
+                                    .ok_or_else(|| VMError::UnknownArgument {
+                                        proccode: proccode.clone(),
+                                        id: id.clone(),
+                                    })
+                            })
+                            .collect::<VMResult<Vec<_>>>()?;
+
+                        // Every argument is evaluated before any of them are
+                        // pushed, so that they all see the caller's arguments.
+                        let values = args
+                            .values()
+                            .map(|arg| self.eval_expr(sprite, arg))
+                            .collect::<VMResult<Vec<_>>>()?;
+                        let mut proc_args = self.proc_args.borrow_mut();
+                        for (name, value) in arg_names.iter().zip(values) {
+                            proc_args
+                                .entry(name.clone())
                                 .or_insert_with(|| Vec::with_capacity(1))
-                                .push(arg);
+                                .push(value);
                         }
+                        drop(proc_args);
 
-                        self.run_proc(sprite, &proc.body)?;
-
-                        for id in args.keys() {
-                            if let Some(stack) = self
-                                .proc_args
-                                .borrow_mut()
-                                .get_mut(proc.arg_names_by_id.get(id).unwrap())
-                            {
-                                stack.pop();
-                            }
-                        }
+                        let warp = proc.warp || in_warp(frames);
+                        frames.push(Frame::Return { arg_names, warp });
+                        let body = StmtRef::Whole(Rc::clone(&proc.body));
+                        frames.push(Frame::sequence(body));
                     }
                 }
 
@@ -195,29 +1089,26 @@ impl VM {
             Statement::DeleteAllOfList { list_id } => {
                 // This could be done with a simple `insert` but that would
                 // throw away the capacity of the old vector.
-                self.lists
+                self.lists_for(sprite, list_id)
                     .borrow_mut()
                     .entry(list_id.clone())
                     .and_modify(Vec::clear)
-                    .or_insert_with(Vec::new);
+                    .or_default();
                 Ok(())
             }
             Statement::DeleteOfList { list_id, index } => {
                 let index = self.eval_expr(sprite, index)?;
                 // This should be a `try` block
                 (|| {
-                    let mut lists = self.lists.borrow_mut();
+                    let mut lists =
+                        self.lists_for(sprite, list_id).borrow_mut();
                     let lst = lists.get_mut(list_id)?;
-                    let index = index.to_index()?;
-                    match index {
-                        Index::Nth(i) => {
-                            if i < lst.len() {
-                                lst.remove(i);
-                            }
-                        }
-                        Index::Last => {
-                            lst.pop();
+                    match self.resolve_index(&index, lst.len()) {
+                        IndexResolution::Nth(i) => {
+                            lst.remove(i);
                         }
+                        IndexResolution::All => lst.clear(),
+                        IndexResolution::Invalid => {}
                     }
                     Some(())
                 })();
@@ -225,13 +1116,32 @@ impl VM {
             }
             Statement::AddToList { list_id, item } => {
                 let item = self.eval_expr(sprite, item)?;
-                self.lists
+                self.lists_for(sprite, list_id)
                     .borrow_mut()
                     .entry(list_id.clone())
                     .or_insert_with(|| Vec::with_capacity(1))
                     .push(item);
                 Ok(())
             }
+            Statement::InsertAtList {
+                list_id,
+                index,
+                item,
+            } => {
+                let index = self.eval_expr(sprite, index)?;
+                // The item is stored exactly as evaluated so that numbers
+                // stay numbers.
+                let item = self.eval_expr(sprite, item)?;
+                let mut lists = self.lists_for(sprite, list_id).borrow_mut();
+                let lst = lists.entry(list_id.clone()).or_default();
+                // The item can also go right after the last one.
+                if let IndexResolution::Nth(i) =
+                    self.resolve_index(&index, lst.len() + 1)
+                {
+                    lst.insert(i, item);
+                }
+                Ok(())
+            }
             Statement::ReplaceItemOfList {
                 list_id,
                 index,
@@ -239,34 +1149,39 @@ impl VM {
             } => {
                 let index = self.eval_expr(sprite, index)?;
                 let item = self.eval_expr(sprite, item)?;
-                let mut lists = self.lists.borrow_mut();
+                let mut lists = self.lists_for(sprite, list_id).borrow_mut();
                 // This should be a `try` block
                 (|| {
                     let lst = lists.get_mut(list_id)?;
-                    let index = index.to_index()?;
-                    let slot = match index {
-                        Index::Nth(i) => lst.get_mut(i),
-                        Index::Last => lst.last_mut(),
-                    }?;
-                    *slot = item;
+                    if let IndexResolution::Nth(i) =
+                        self.resolve_index(&index, lst.len())
+                    {
+                        lst[i] = item;
+                    }
                     Some(())
                 })();
                 Ok(())
             }
             Statement::SetVariable { var_id, value } => {
                 let value = self.eval_expr(sprite, value)?;
-                self.vars.borrow_mut().insert(var_id.clone(), value);
+                self.vars_for(sprite, var_id)
+                    .borrow_mut()
+                    .insert(var_id.clone(), value);
                 Ok(())
             }
             Statement::ChangeVariableBy { var_id, value } => {
                 let value = self.eval_expr(sprite, value)?.to_num();
-                let mut vars = self.vars.borrow_mut();
+                let mut vars = self.vars_for(sprite, var_id).borrow_mut();
                 let old = vars.get(var_id).map_or(0.0, Value::to_num);
                 vars.insert(var_id.clone(), Value::Num(old + value));
                 Ok(())
             }
             Statement::StopAll => Err(VMError::StopAll),
             Statement::StopThisScript => Err(VMError::StopThisScript),
+            Statement::StopOtherScripts => {
+                sched.stop_others(sprite);
+                Ok(())
+            }
         }
     }
 
@@ -275,16 +1190,14 @@ impl VM {
         sprite: &Sprite,
         expr: &Expr,
     ) -> VMResult<Value> {
-        let mathop = |num: &Expr, f: fn(f64) -> f64| {
-            let num = self.eval_expr(sprite, num)?;
-            Ok(Value::Num(f(num.to_num())))
-        };
-
         match expr {
             Expr::Lit(lit) => Ok(lit.clone()),
-            Expr::GetVar { var_id } => {
-                Ok(self.vars.borrow().get(var_id).cloned().unwrap_or_default())
-            }
+            Expr::GetVar { var_id } => Ok(self
+                .vars_for(sprite, var_id)
+                .borrow()
+                .get(var_id)
+                .cloned()
+                .unwrap_or_default()),
             Expr::ProcArgStringNumber { name } => Ok(self
                 .proc_args
                 .borrow()
@@ -295,132 +1208,522 @@ impl VM {
                 let index = self.eval_expr(sprite, index)?;
                 // This should be a `try` block
                 Ok((|| {
-                    let lists = self.lists.borrow();
+                    let lists = self.lists_for(sprite, list_id).borrow();
                     let lst = lists.get(list_id)?;
-                    let index = index.to_index()?;
-                    match index {
-                        Index::Nth(i) => lst.get(i),
-                        Index::Last => lst.last(),
+                    match self.resolve_index(&index, lst.len()) {
+                        IndexResolution::Nth(i) => Some(lst[i].clone()),
+                        IndexResolution::All | IndexResolution::Invalid => None,
                     }
-                    .cloned()
                 })()
                 .unwrap_or_default())
             }
+            Expr::ItemNumOfList { list_id, item } => {
+                let item = self.eval_expr(sprite, item)?;
+                let lists = self.lists_for(sprite, list_id).borrow();
+                // Items are compared like with `=`, so the number 5 and the
+                // string "5" are the same item.
+                let position = lists.get(list_id).and_then(|lst| {
+                    lst.iter().position(|it| it.compare(&item).is_eq())
+                });
+                Ok(Value::Num(position.map_or(0.0, |i| (i + 1) as f64)))
+            }
             Expr::LengthOfList { list_id } => Ok(Value::Num(
-                self.lists
+                self.lists_for(sprite, list_id)
                     .borrow()
                     .get(list_id)
                     .map_or(0.0, |lst| Vec::len(lst) as f64),
             )),
-            Expr::Abs(num) => mathop(num, f64::abs),
-            Expr::Floor(num) => mathop(num, f64::floor),
-            Expr::Ceiling(num) => mathop(num, f64::ceil),
-            Expr::Sqrt(num) => mathop(num, f64::sqrt),
-            Expr::Sin(num) => mathop(num, |n| n.to_radians().sin()),
-            Expr::Cos(num) => mathop(num, |n| n.to_radians().cos()),
-            Expr::Tan(num) => mathop(num, |n| n.to_radians().tan()),
-            Expr::Asin(num) => mathop(num, |n| n.to_degrees().asin()),
-            Expr::Acos(num) => mathop(num, |n| n.to_degrees().acos()),
-            Expr::Atan(num) => mathop(num, |n| n.to_degrees().atan()),
-            Expr::Ln(num) => mathop(num, f64::ln),
-            Expr::Log(num) => mathop(num, f64::log10),
-            Expr::EExp(num) => mathop(num, f64::exp),
-            Expr::TenExp(num) => mathop(num, |n| 10.0f64.powf(n)),
-            Expr::Call { opcode, inputs } => {
-                self.eval_funcall(sprite, opcode, inputs)
+            Expr::ListContents { list_id } => {
+                let lists = self.lists_for(sprite, list_id).borrow();
+                let Some(lst) = lists.get(list_id) else {
+                    return Ok(Value::String("".into()));
+                };
+                // Scratch only leaves out the separating spaces if every item
+                // is a single letter.
+                let all_single_letters = lst.iter().all(|item| {
+                    matches!(item, Value::String(s) if s.chars().count() == 1)
+                });
+                let separator = if all_single_letters { "" } else { " " };
+                Ok(Value::String(
+                    lst.iter()
+                        .map(Value::to_cow_str)
+                        .collect::<Vec<_>>()
+                        .join(separator)
+                        .into(),
+                ))
+            }
+            Expr::MathOp { op, num } => {
+                let f = mathop(op)
+                    .ok_or_else(|| VMError::UnknownMathOp(op.clone()))?;
+                let num = self.eval_expr(sprite, num)?;
+                // Adding positive zero turns `-0.0` into `0.0` without
+                // affecting any other value, since Scratch never displays
+                // negative zero.
+                Ok(Value::Num(f(num.to_num()) + 0.0))
             }
+            Expr::AttributeOf { property, object } => {
+                let object = self.eval_expr(sprite, object)?;
+                let object = object.to_cow_str();
+                let target = if object == "_stage_" {
+                    self.sprites.values().find(|spr| spr.is_stage)
+                } else {
+                    self.sprites.get(&*object)
+                };
+                let Some(target) = target else {
+                    return Ok(Value::Num(0.0));
+                };
+                Ok(match &**property {
+                    "x position" if !target.is_stage => {
+                        Value::Num(position(target).0)
+                    }
+                    "y position" if !target.is_stage => {
+                        Value::Num(position(target).1)
+                    }
+                    "direction" if !target.is_stage => {
+                        Value::Num(target.direction.get())
+                    }
+                    "costume #" if !target.is_stage => costume_number(target),
+                    "costume name" if !target.is_stage => costume_name(target),
+                    "size" if !target.is_stage => Value::Num(size(target)),
+                    // Older projects call backdrops backgrounds.
+                    "backdrop #" | "background #" if target.is_stage => {
+                        costume_number(target)
+                    }
+                    "backdrop name" if target.is_stage => costume_name(target),
+                    // Anything else is the name of one of the target's own
+                    // variables.
+                    _ => target
+                        .variable_ids_by_name
+                        .get(property)
+                        .and_then(|id| {
+                            self.vars_for(target, id).borrow().get(id).cloned()
+                        })
+                        .unwrap_or(Value::Num(0.0)),
+                })
+            }
+            Expr::Call {
+                opcode,
+                inputs,
+                fields,
+            } => self.eval_funcall(sprite, opcode, inputs, fields),
+        }
+    }
+
+    /// Returns the variables that `var_id` is stored in when `sprite` uses
+    /// it, which are the sprite's own if it has a local variable with that
+    /// ID and the global ones otherwise.
+    fn vars_for<'a>(
+        &'a self,
+        sprite: &'a Sprite,
+        var_id: &str,
+    ) -> &'a RefCell<HashMap<EcoString, Value>> {
+        if sprite.vars.borrow().contains_key(var_id) {
+            &sprite.vars
+        } else {
+            &self.vars
+        }
+    }
+
+    /// Like `vars_for`, but for lists.
+    fn lists_for<'a>(
+        &'a self,
+        sprite: &'a Sprite,
+        list_id: &str,
+    ) -> &'a RefCell<HashMap<EcoString, Vec<Value>>> {
+        if sprite.lists.borrow().contains_key(list_id) {
+            &sprite.lists
+        } else {
+            &self.lists
         }
     }
 
+    /// Works out which of the `len` items of a list `index` refers to, the
+    /// way Scratch does. Numbers count from 1 and are rounded down, `"last"`
+    /// is the last item, `"random"` and `"any"` pick a random item and
+    /// `"all"` refers to every item.
+    pub fn resolve_index(&self, index: &Value, len: usize) -> IndexResolution {
+        let nth = match &*index.to_cow_str() {
+            "all" => return IndexResolution::All,
+            "last" => len.checked_sub(1),
+            "random" | "any" => {
+                (len != 0).then(|| self.rng().gen_range(0..len))
+            }
+            _ => {
+                let n = index.to_num().floor();
+                (n >= 1.0 && n <= len as f64).then(|| n as usize - 1)
+            }
+        };
+        nth.map_or(IndexResolution::Invalid, IndexResolution::Nth)
+    }
+
     fn input(
         &self,
         sprite: &Sprite,
         inputs: &HashMap<EcoString, Expr>,
         name: &str,
     ) -> VMResult<Value> {
-        self.eval_expr(sprite, inputs.get(name).unwrap())
+        let input = inputs
+            .get(name)
+            .ok_or_else(|| VMError::MissingInput(name.to_owned()))?;
+        self.eval_expr(sprite, input)
     }
 
-    fn call_builtin_statement(
-        &self,
-        sprite: &Sprite,
-        opcode: &str,
-        inputs: &HashMap<EcoString, Expr>,
-    ) -> VMResult<()> {
-        match opcode {
-            "event_broadcastandwait" => {
+    /// Starts a thread for every script that receives the broadcast `name`
+    /// and returns their IDs. Scripts that are already running start over.
+    fn start_broadcast(&self, sched: &mut Scheduler, name: &str) -> Vec<u64> {
+        let clones = self.clones.borrow();
+        let mut ids = Vec::new();
+        for spr in self.sprites.values().chain(clones.iter()) {
+            if let Some(receivers) = spr.procs.broadcasts.get(name) {
+                for script in receivers {
+                    ids.push(sched.restart(Rc::clone(spr), script));
+                }
+            }
+        }
+        ids
+    }
+
+    fn start_green_flag(&self, sched: &mut Scheduler) {
+        sched.stop_all();
+        self.delete_clones();
+        for spr in self.sprites.values() {
+            for script in &spr.procs.when_flag_clicked {
+                sched.start(Rc::clone(spr), script);
+            }
+        }
+    }
+
+    /// Starts the scripts whose hats respond to `event`.
+    fn handle_event(&self, sched: &mut Scheduler, event: &Event) {
+        match event {
+            Event::GreenFlag => self.start_green_flag(sched),
+            Event::KeyPressed(key) => {
+                // Key hats don't restart scripts that are still running from
+                // an earlier press.
+                let clones = self.clones.borrow();
+                for spr in self.sprites.values().chain(clones.iter()) {
+                    let scripts = [key.as_str(), "any"]
+                        .into_iter()
+                        .filter_map(|key| spr.procs.when_key_pressed.get(key))
+                        .flatten();
+                    for script in scripts {
+                        sched.start_once(Rc::clone(spr), script);
+                    }
+                }
+            }
+            Event::Clicked(name) => {
+                if let Some(spr) = self.sprites.get(name.as_str()) {
+                    for script in &spr.procs.when_clicked {
+                        sched.restart(Rc::clone(spr), script);
+                    }
+                }
+            }
+            Event::Broadcast(name) => {
+                self.start_broadcast(sched, name);
+            }
+        }
+    }
+
+    /// Returns `None` if `original` can't be cloned.
+    fn create_clone(&self, original: &Sprite) -> Option<Rc<Sprite>> {
+        if original.is_stage || self.clones.borrow().len() >= MAX_CLONES {
+            return None;
+        }
+        let clone = Rc::new(original.make_clone());
+        self.clones.borrow_mut().push(Rc::clone(&clone));
+        Some(clone)
+    }
+
+    fn delete_clones(&self) {
+        for clone in self.clones.take() {
+            clone.deleted.set(true);
+        }
+    }
+
+    /// Speech bubbles aren't drawn, so messages are printed instead, with
+    /// thoughts in parentheses to tell them apart.
+    fn say(&self, message: &Value, think: bool) -> VMResult<()> {
+        if think {
+            writeln!(self.output(), "({message})")?;
+        } else {
+            writeln!(self.output(), "{message}")?;
+        }
+        Ok(())
+    }
+
+    fn clock(&self) -> &dyn Clock {
+        self.clock
+            .get_or_init(|| Box::new(SystemClock::default()))
+            .as_ref()
+    }
+
+    fn rng(&self) -> RefMut<'_, SmallRng> {
+        RefMut::map(self.rng.borrow_mut(), |rng| {
+            rng.get_or_insert_with(SmallRng::from_entropy)
+        })
+    }
+
+    fn output(&self) -> RefMut<'_, dyn Write> {
+        RefMut::map(self.output.borrow_mut(), |output| &mut *output.0)
+    }
+
+    fn set_position(&self, sprite: &Sprite, x: f64, y: f64) {
+        // The stage can't move, so motion blocks don't affect it.
+        if sprite.is_stage {
+            return;
+        }
+        if self.fencing {
+            // Sprite dimensions aren't tracked, so this keeps the sprite's
+            // center on the stage rather than a part of its costume.
+            sprite.x.set(x.clamp(-STAGE_WIDTH / 2.0, STAGE_WIDTH / 2.0));
+            sprite
+                .y
+                .set(y.clamp(-STAGE_HEIGHT / 2.0, STAGE_HEIGHT / 2.0));
+        } else {
+            sprite.x.set(x);
+            sprite.y.set(y);
+        }
+    }
+
+    /// Runs the built-in statements that start other threads or take time,
+    /// and leaves the rest to `call_builtin_statement`.
+    fn start_builtin_statement(
+        &self,
+        sched: &mut Scheduler,
+        sprite: &Rc<Sprite>,
+        frames: &mut Vec<Frame>,
+        stmt: &StmtRef,
+        opcode: &str,
+        inputs: &HashMap<EcoString, Expr>,
+    ) -> VMResult<()> {
+        match opcode {
+            "event_broadcast" | "event_broadcastandwait" => {
                 let broadcast_input =
                     self.input(sprite, inputs, "BROADCAST_INPUT")?;
-                let broadcast_name = broadcast_input.to_cow_str();
-                for spr in self.sprites.values() {
-                    if let Some(receivers) =
-                        spr.procs.broadcasts.get(&*broadcast_name)
-                    {
-                        for rec in receivers {
-                            self.run_proc(sprite, rec)?;
-                        }
-                    }
+                let ids =
+                    self.start_broadcast(sched, &broadcast_input.to_cow_str());
+                if opcode == "event_broadcastandwait" {
+                    frames.push(Frame::WaitForThreads { ids });
                 }
                 Ok(())
             }
+            "control_create_clone_of" => {
+                let option = self.input(sprite, inputs, "CLONE_OPTION")?;
+                let option = option.to_cow_str();
+                let original = if option == "_myself_" {
+                    Some(sprite)
+                } else {
+                    self.sprites.get(&*option)
+                };
+                let Some(clone) =
+                    original.and_then(|original| self.create_clone(original))
+                else {
+                    return Ok(());
+                };
+                for script in &clone.procs.start_as_clone {
+                    sched.start(Rc::clone(&clone), script);
+                }
+                Ok(())
+            }
+            "control_delete_this_clone" => {
+                if !sprite.is_clone {
+                    return Ok(());
+                }
+                sprite.deleted.set(true);
+                self.clones
+                    .borrow_mut()
+                    .retain(|clone| !Rc::ptr_eq(clone, sprite));
+                // The clone's other threads stop the next time the scheduler
+                // gets to them.
+                frames.clear();
+                Ok(())
+            }
+            "control_wait_until" => {
+                frames.push(Frame::WaitUntil { stmt: stmt.clone() });
+                Ok(())
+            }
+            "motion_glidesecstoxy" => {
+                let duration = self.input(sprite, inputs, "SECS")?.to_num();
+                let x = self.input(sprite, inputs, "X")?.to_num();
+                let y = self.input(sprite, inputs, "Y")?.to_num();
+                frames.push(Frame::Glide {
+                    from: (sprite.x.get(), sprite.y.get()),
+                    to: (x, y),
+                    start: self.clock().now(),
+                    duration: duration.max(0.0),
+                });
+                Ok(())
+            }
+            "looks_sayforsecs" | "looks_thinkforsecs" => {
+                let message = self.input(sprite, inputs, "MESSAGE")?;
+                let secs = self.input(sprite, inputs, "SECS")?.to_num();
+                self.say(&message, opcode == "looks_thinkforsecs")?;
+                self.output().flush()?;
+                frames.push(Frame::Wait {
+                    until: self.clock().now() + secs.max(0.0),
+                    yielded: false,
+                });
+                Ok(())
+            }
+            "control_wait" => {
+                let duration = self.input(sprite, inputs, "DURATION")?;
+                // Whatever was printed before waiting should be visible while
+                // waiting.
+                self.output().flush()?;
+                frames.push(Frame::Wait {
+                    until: self.clock().now() + duration.to_num().max(0.0),
+                    yielded: false,
+                });
+                Ok(())
+            }
+            "sensing_askandwait" => {
+                let question = self.input(sprite, inputs, "QUESTION")?;
+                let turn = Rc::new(());
+                self.asks.borrow_mut().push_back(Rc::downgrade(&turn));
+                frames.push(Frame::Ask {
+                    question,
+                    turn,
+                    asked: false,
+                });
+                Ok(())
+            }
+            _ => self.call_builtin_statement(sprite, opcode, inputs),
+        }
+    }
+
+    /// Whether the `ask` block that holds `turn` is the oldest one that is
+    /// still waiting to be answered.
+    fn is_turn_to_ask(&self, turn: &Rc<()>) -> bool {
+        let mut asks = self.asks.borrow_mut();
+        // Asks of threads that were stopped can't be answered anymore.
+        while asks.front().is_some_and(|ask| ask.strong_count() == 0) {
+            asks.pop_front();
+        }
+        asks.front()
+            .is_some_and(|ask| ptr::eq(ask.as_ptr(), Rc::as_ptr(turn)))
+    }
+
+    fn show_question(&self, question: &Value) -> VMResult<()> {
+        let mut output = self.output();
+        write!(output, "{question}")?;
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Reads the answer to the question that was shown last. Every thread
+    /// runs on the same OS thread, so no other script runs while this waits
+    /// for the user to type an answer.
+    fn read_answer(&self) -> VMResult<()> {
+        let mut answer = String::new();
+        self.answers.borrow_mut().0.read_line(&mut answer)?;
+        let answer = answer.trim().to_owned();
+        if self.echo_answers {
+            writeln!(self.output(), "{answer}")?;
+        }
+        self.answer.replace(answer);
+        Ok(())
+    }
+
+    fn call_builtin_statement(
+        &self,
+        sprite: &Sprite,
+        opcode: &str,
+        inputs: &HashMap<EcoString, Expr>,
+    ) -> VMResult<()> {
+        match opcode {
+            "motion_movesteps" => {
+                let steps = self.input(sprite, inputs, "STEPS")?.to_num();
+                let direction = sprite.direction.get().to_radians();
+                self.set_position(
+                    sprite,
+                    steps.mul_add(direction.sin(), sprite.x.get()),
+                    steps.mul_add(direction.cos(), sprite.y.get()),
+                );
+                Ok(())
+            }
+            "motion_turnright" | "motion_turnleft" => {
+                let degrees = self.input(sprite, inputs, "DEGREES")?.to_num();
+                let degrees = if opcode == "motion_turnleft" {
+                    -degrees
+                } else {
+                    degrees
+                };
+                set_direction(sprite, sprite.direction.get() + degrees);
+                Ok(())
+            }
+            "motion_pointindirection" => {
+                let direction =
+                    self.input(sprite, inputs, "DIRECTION")?.to_num();
+                set_direction(sprite, direction);
+                Ok(())
+            }
             "motion_gotoxy" => {
                 let x = self.input(sprite, inputs, "X")?.to_num();
                 let y = self.input(sprite, inputs, "Y")?.to_num();
-                sprite.x.set(x);
-                sprite.y.set(y);
+                self.set_position(sprite, x, y);
                 Ok(())
             }
             "motion_setx" => {
                 let x = self.input(sprite, inputs, "X")?.to_num();
-                sprite.x.set(x);
+                self.set_position(sprite, x, sprite.y.get());
                 Ok(())
             }
             "motion_sety" => {
                 let y = self.input(sprite, inputs, "Y")?.to_num();
-                sprite.y.set(y);
+                self.set_position(sprite, sprite.x.get(), y);
                 Ok(())
             }
             "motion_changexby" => {
                 let dx = self.input(sprite, inputs, "DX")?.to_num();
-                sprite.x.set(sprite.x.get() + dx);
+                self.set_position(sprite, sprite.x.get() + dx, sprite.y.get());
                 Ok(())
             }
             "motion_changeyby" => {
                 let dy = self.input(sprite, inputs, "DY")?.to_num();
-                sprite.y.set(sprite.y.get() + dy);
+                self.set_position(sprite, sprite.x.get(), sprite.y.get() + dy);
                 Ok(())
             }
-            "pen_clear"
-            | "pen_stamp"
-            | "pen_setPenSizeTo"
-            | "pen_penDown"
-            | "pen_penUp"
-            | "looks_show"
-            | "looks_hide"
-            | "looks_setsizeto"
-            | "looks_switchcostumeto" => {
+            "pen_clear" | "pen_stamp" | "pen_setPenSizeTo" | "pen_penDown"
+            | "pen_penUp" => {
                 // TODO: Actually do something
                 Ok(())
             }
-            "looks_say" => {
-                let message = self.input(sprite, inputs, "MESSAGE")?;
-                println!("{message}");
+            "looks_setsizeto" => {
+                let size = self.input(sprite, inputs, "SIZE")?.to_num();
+                set_size(sprite, size);
                 Ok(())
             }
-            "sensing_askandwait" => {
-                let question = self.input(sprite, inputs, "QUESTION")?;
-                print!("{question}");
-                let mut answer = String::new();
-                std::io::stdout().flush()?;
-                std::io::stdin().read_line(&mut answer)?;
-                self.answer.replace(answer.trim().to_owned());
+            "looks_changesizeby" => {
+                let change = self.input(sprite, inputs, "CHANGE")?.to_num();
+                set_size(sprite, sprite.size.get() + change);
                 Ok(())
             }
-            "control_wait" => {
-                let duration = self.input(sprite, inputs, "DURATION")?;
-                std::thread::sleep(time::Duration::from_micros(
-                    (duration.to_num() * 1.0e6) as u64,
-                ));
+            "looks_switchcostumeto" => {
+                let costume = self.input(sprite, inputs, "COSTUME")?;
+                switch_costume(sprite, &costume);
+                Ok(())
+            }
+            "looks_nextcostume" => {
+                let count = sprite.costumes.len();
+                if count != 0 {
+                    sprite.costume.set((sprite.costume.get() + 1) % count);
+                }
+                Ok(())
+            }
+            "looks_show" => {
+                sprite.visible.set(true);
+                Ok(())
+            }
+            "looks_hide" => {
+                sprite.visible.set(false);
+                Ok(())
+            }
+            "looks_say" | "looks_think" => {
+                let message = self.input(sprite, inputs, "MESSAGE")?;
+                self.say(&message, opcode == "looks_think")
+            }
+            "sensing_resettimer" => {
+                self.timer.set(self.clock().now());
                 Ok(())
             }
             _ => Err(VMError::UnknownOpcode(opcode.to_owned())),
@@ -432,6 +1735,7 @@ impl VM {
         sprite: &Sprite,
         opcode: &str,
         inputs: &HashMap<EcoString, Expr>,
+        fields: &HashMap<EcoString, Value>,
     ) -> VMResult<Value> {
         let comparison = |ord: cmp::Ordering| {
             let lhs = self.input(sprite, inputs, "OPERAND1")?;
@@ -465,52 +1769,2171 @@ impl VM {
             "operator_subtract" => bin_num_op(ops::Sub::sub),
             "operator_multiply" => bin_num_op(ops::Mul::mul),
             "operator_divide" => bin_num_op(ops::Div::div),
+            "operator_mod" => bin_num_op(|lhs, rhs| {
+                // Unlike `%`, Scratch's modulo takes the sign of the divisor.
+                let rem = lhs % rhs;
+                if rem / rhs < 0.0 {
+                    rem + rhs
+                } else {
+                    rem
+                }
+            }),
+            "operator_round" => {
+                let n = self.input(sprite, inputs, "NUM")?.to_num();
+                // Scratch rounds halves towards positive infinity (so -2.5
+                // becomes -2), whereas `f64::round` rounds them away from
+                // zero.
+                let floor = n.floor();
+                let rounded =
+                    if n - floor >= 0.5 { floor + 1.0 } else { floor };
+                Ok(Value::Num(rounded + 0.0))
+            }
+            "operator_random" => {
+                let from = self.input(sprite, inputs, "FROM")?.to_num();
+                let to = self.input(sprite, inputs, "TO")?.to_num();
+                let (low, high) =
+                    if from > to { (to, from) } else { (from, to) };
+                let mut rng = self.rng();
+                let n = if low.fract() == 0.0 && high.fract() == 0.0 {
+                    rng.gen_range(low as i64..=high as i64) as f64
+                } else {
+                    rng.gen::<f64>().mul_add(high - low, low)
+                };
+                drop(rng);
+                Ok(Value::Num(n))
+            }
             "operator_length" => {
-                let s =
-                    self.eval_expr(sprite, inputs.get("STRING").unwrap())?;
+                let s = self.input(sprite, inputs, "STRING")?;
                 Ok(Value::Num(s.to_cow_str().len() as f64))
             }
             "operator_join" => {
                 let lhs = self.input(sprite, inputs, "STRING1")?;
                 let rhs = self.input(sprite, inputs, "STRING2")?;
-                Ok(Value::String((lhs.to_cow_str() + rhs.to_cow_str()).into()))
-            }
-            "motion_xposition" => {
-                // FIXME: This should be rounded
-                Ok(Value::Num(sprite.x.get()))
+                let (lhs, rhs) = (lhs.to_cow_str(), rhs.to_cow_str());
+                if let Some(max) = self.max_string_len {
+                    if lhs.len() + rhs.len() > max {
+                        return Err(VMError::StringTooLong(max));
+                    }
+                }
+                Ok(Value::String((lhs + rhs).into()))
             }
-            "motion_yposition" => {
-                // FIXME: This should be rounded
-                Ok(Value::Num(sprite.y.get()))
+            "operator_contains" => {
+                let haystack = self.input(sprite, inputs, "STRING1")?;
+                let needle = self.input(sprite, inputs, "STRING2")?;
+                // Scratch ignores case here.
+                Ok(Value::Bool(
+                    haystack
+                        .to_cow_str()
+                        .to_lowercase()
+                        .contains(&needle.to_cow_str().to_lowercase()),
+                ))
             }
+            "motion_xposition" => Ok(Value::Num(position(sprite).0)),
+            "motion_yposition" => Ok(Value::Num(position(sprite).1)),
+            "motion_direction" => Ok(Value::Num(sprite.direction.get())),
             "operator_letter_of" => {
                 let s = self.input(sprite, inputs, "STRING")?;
                 let index = self.input(sprite, inputs, "LETTER")?;
-                Ok(
-                    // This should be a `try` block
-                    (|| {
-                        let index = index.to_index()?;
-                        match index {
-                            Index::Nth(i) => Some(Value::String(
-                                s.to_cow_str()
-                                    .chars()
-                                    .skip(i)
-                                    .take(1)
-                                    .collect(),
-                            )),
-                            Index::Last => None,
-                        }
-                    })()
-                    .unwrap_or_default(),
-                )
+                let s = s.to_cow_str();
+                Ok(match self.resolve_index(&index, s.chars().count()) {
+                    IndexResolution::Nth(i) => {
+                        Value::String(s.chars().skip(i).take(1).collect())
+                    }
+                    IndexResolution::All | IndexResolution::Invalid => {
+                        Value::default()
+                    }
+                })
             }
             "sensing_answer" => {
                 Ok(Value::String(self.answer.borrow().as_str().into()))
             }
             "sensing_timer" => {
-                Ok(Value::Num(self.timer.get().elapsed().as_secs_f64()))
+                Ok(Value::Num(self.clock().now() - self.timer.get()))
+            }
+            "sensing_keypressed" => {
+                let key = self.input(sprite, inputs, "KEY_OPTION")?;
+                Ok(Value::Bool(
+                    self.input_source.key_pressed(&key.to_cow_str()),
+                ))
+            }
+            "sensing_mousex" => Ok(Value::Num(self.input_source.mouse_pos().0)),
+            "sensing_mousey" => Ok(Value::Num(self.input_source.mouse_pos().1)),
+            "sensing_mousedown" => {
+                Ok(Value::Bool(self.input_source.mouse_down()))
+            }
+            "sensing_loudness" => Ok(Value::Num(self.input_source.loudness())),
+            "music_getTempo" => Ok(Value::Num(self.tempo.get())),
+            "looks_costumenumbername" => {
+                match fields.get("NUMBER_NAME").map(Value::to_cow_str) {
+                    Some(number_name) if number_name == "name" => {
+                        Ok(costume_name(sprite))
+                    }
+                    _ => Ok(costume_number(sprite)),
+                }
+            }
+            "looks_size" => Ok(Value::Num(size(sprite))),
+            // Dropdown menus are shadow blocks whose value is their only
+            // field.
+            "looks_costume"
+            | "looks_backdrops"
+            | "motion_goto_menu"
+            | "motion_glideto_menu"
+            | "motion_pointtowards_menu"
+            | "sensing_touchingobjectmenu"
+            | "sensing_distancetomenu"
+            | "sound_sounds_menu" => {
+                Ok(fields.values().next().cloned().unwrap_or_default())
             }
             _ => Err(VMError::UnknownOpcode(opcode.to_owned())),
         }
     }
 }
+
+/// The position of `sprite` as the reporters show it, rounded to whole steps.
+const fn position(sprite: &Sprite) -> (f64, f64) {
+    (sprite.x.get().round(), sprite.y.get().round())
+}
+
+/// The size of `sprite` as the reporters show it, rounded to a whole percent.
+const fn size(sprite: &Sprite) -> f64 {
+    sprite.size.get().round()
+}
+
+/// The number of the current costume of `sprite`, counting from 1.
+const fn costume_number(sprite: &Sprite) -> Value {
+    Value::Num((sprite.costume.get() + 1) as f64)
+}
+
+/// The name of the current costume of `sprite`.
+fn costume_name(sprite: &Sprite) -> Value {
+    sprite
+        .costumes
+        .get(sprite.costume.get())
+        .map_or_else(Value::default, |name| Value::String(name.as_str().into()))
+}
+
+/// Resizes `sprite` to `size` percent, kept between `MIN_SIZE` and
+/// `MAX_SIZE`. The stage can't be resized.
+fn set_size(sprite: &Sprite, size: f64) {
+    if !sprite.is_stage {
+        sprite.size.set(size.clamp(MIN_SIZE, MAX_SIZE));
+    }
+}
+
+/// Switches the costume of `sprite` the way Scratch does: numbers are
+/// 1-based indices that wrap around, and strings are looked up by name before
+/// being treated as numbers. Anything else is ignored.
+fn switch_costume(sprite: &Sprite, costume: &Value) {
+    let count = sprite.costumes.len();
+    if count == 0 {
+        return;
+    }
+    let index = |n: f64| {
+        if n.is_finite() {
+            (n - 1.0).round().rem_euclid(count as f64) as usize
+        } else {
+            0
+        }
+    };
+    let name = costume.to_cow_str();
+    let current = sprite.costume.get();
+    let costume = match costume {
+        Value::Num(n) => index(*n),
+        _ => match sprite.costumes.iter().position(|c| **c == *name) {
+            Some(i) => i,
+            None if name == "next costume" => (current + 1) % count,
+            None if name == "previous costume" => (current + count - 1) % count,
+            None => match name.trim().parse() {
+                Ok(n) => index(n),
+                Err(_) => return,
+            },
+        },
+    };
+    sprite.costume.set(costume);
+}
+
+/// Points `sprite` in `direction`, wrapped into the range (-180, 180] the
+/// way Scratch reports it.
+fn set_direction(sprite: &Sprite, direction: f64) {
+    // The stage can't turn either. Non-finite directions are ignored since
+    // a NaN would otherwise stick to the sprite forever.
+    if sprite.is_stage || !direction.is_finite() {
+        return;
+    }
+    let wrapped = (direction + 180.0).rem_euclid(360.0) - 180.0;
+    sprite.direction.set(if wrapped <= -180.0 {
+        wrapped + 360.0
+    } else {
+        wrapped
+    });
+}
+
+/// Looks up the function computed by the `operator_mathop` operator `op`.
+fn mathop(op: &str) -> Option<fn(f64) -> f64> {
+    let f: fn(f64) -> f64 = match op {
+        "abs" => f64::abs,
+        "floor" => f64::floor,
+        "ceiling" => f64::ceil,
+        "sqrt" => f64::sqrt,
+        "sin" => |n| n.to_radians().sin(),
+        "cos" => |n| n.to_radians().cos(),
+        "tan" => |n| n.to_radians().tan(),
+        "asin" => |n| n.asin().to_degrees(),
+        "acos" => |n| n.acos().to_degrees(),
+        "atan" => |n| n.atan().to_degrees(),
+        "ln" => f64::ln,
+        "log" => f64::log10,
+        "e ^" => f64::exp,
+        "10 ^" => |n| 10.0f64.powf(n),
+        _ => return None,
+    };
+    Some(f)
+}
+
+/// Whether the VM knows how to run `opcode`.
+pub fn is_builtin_statement(opcode: &str) -> bool {
+    matches!(
+        opcode,
+        "event_broadcast"
+            | "event_broadcastandwait"
+            | "control_create_clone_of"
+            | "control_delete_this_clone"
+            | "motion_movesteps"
+            | "motion_turnright"
+            | "motion_turnleft"
+            | "motion_pointindirection"
+            | "motion_gotoxy"
+            | "motion_glidesecstoxy"
+            | "motion_setx"
+            | "motion_sety"
+            | "motion_changexby"
+            | "motion_changeyby"
+            | "pen_clear"
+            | "pen_stamp"
+            | "pen_setPenSizeTo"
+            | "pen_penDown"
+            | "pen_penUp"
+            | "looks_show"
+            | "looks_hide"
+            | "looks_setsizeto"
+            | "looks_changesizeby"
+            | "looks_switchcostumeto"
+            | "looks_nextcostume"
+            | "looks_say"
+            | "looks_think"
+            | "looks_sayforsecs"
+            | "looks_thinkforsecs"
+            | "sensing_askandwait"
+            | "control_wait_until"
+            | "sensing_resettimer"
+            | "control_wait"
+    )
+}
+
+/// Whether `VM::eval_funcall` knows how to evaluate `opcode`.
+fn is_builtin_reporter(opcode: &str) -> bool {
+    matches!(
+        opcode,
+        "operator_equals"
+            | "operator_lt"
+            | "operator_gt"
+            | "operator_not"
+            | "operator_or"
+            | "operator_and"
+            | "operator_add"
+            | "operator_subtract"
+            | "operator_multiply"
+            | "operator_divide"
+            | "operator_mod"
+            | "operator_round"
+            | "operator_random"
+            | "operator_length"
+            | "operator_join"
+            | "operator_contains"
+            | "motion_xposition"
+            | "motion_yposition"
+            | "motion_direction"
+            | "operator_letter_of"
+            | "sensing_answer"
+            | "sensing_timer"
+            | "sensing_keypressed"
+            | "sensing_mousex"
+            | "sensing_mousey"
+            | "sensing_mousedown"
+            | "sensing_loudness"
+            | "music_getTempo"
+            | "looks_costumenumbername"
+            | "looks_size"
+            | "looks_costume"
+            | "looks_backdrops"
+            | "motion_goto_menu"
+            | "motion_glideto_menu"
+            | "motion_pointtowards_menu"
+            | "sensing_touchingobjectmenu"
+            | "sensing_distancetomenu"
+            | "sound_sounds_menu"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use serde_json::{json, Value as Json};
+
+    /// Loads a project with one sprite named "Sprite" that has `blocks`,
+    /// using a virtual clock so that waits resolve instantly.
+    fn project(blocks: Json) -> VM {
+        let mut sprite = json!({ "name": "Sprite" });
+        sprite["blocks"] = blocks;
+        project_with_sprite(sprite)
+    }
+
+    /// Like `project`, but with the whole target of the sprite given.
+    fn project_with_sprite(sprite: Json) -> VM {
+        project_with_sprites(vec![sprite])
+    }
+
+    /// Like `project_with_sprite`, but with any number of sprites.
+    fn project_with_sprites(mut sprites: Vec<Json>) -> VM {
+        let stage = json!({ "name": "Stage", "isStage": true, "blocks": {} });
+        sprites.insert(0, stage);
+        let project = json!({ "targets": Json::Array(sprites) });
+        let mut vm = serde_json::from_str::<VM>(&project.to_string())
+            .expect("invalid test project");
+        vm.set_clock(Box::<VirtualClock>::default());
+        vm.set_output(Box::new(std::io::sink()));
+        vm
+    }
+
+    fn call<const N: usize>(opcode: &str, inputs: [(&str, Expr); N]) -> Expr {
+        Expr::Call {
+            opcode: opcode.to_owned(),
+            inputs: inputs
+                .into_iter()
+                .map(|(name, input)| (name.into(), input))
+                .collect(),
+            fields: HashMap::new(),
+        }
+    }
+
+    const fn num(n: f64) -> Expr {
+        Expr::Lit(Value::Num(n))
+    }
+
+    /// Evaluates `expr` in an empty project and returns how it displays.
+    fn eval(expr: &Expr) -> String {
+        project(json!({}))
+            .eval_reporter("Sprite", expr)
+            .expect("evaluation failed")
+            .to_string()
+    }
+
+    fn binary_op(opcode: &str, lhs: f64, rhs: f64) -> String {
+        eval(&call(opcode, [("NUM1", num(lhs)), ("NUM2", num(rhs))]))
+    }
+
+    #[test]
+    fn pending_threads_tell_waiting_from_running() {
+        let vm = project(json!({
+            "waiter": { "opcode": "event_whenflagclicked", "next": "wait" },
+            "wait": {
+                "opcode": "control_wait",
+                "inputs": { "DURATION": [1, [5, "10"]] },
+            },
+            "looper": { "opcode": "event_whenflagclicked", "next": "loop" },
+            "loop": {
+                "opcode": "control_forever",
+                "inputs": { "SUBSTACK": [2, "move"] },
+            },
+            "move": {
+                "opcode": "motion_changexby",
+                "inputs": { "DX": [1, [4, "1"]] },
+            },
+            "finisher": { "opcode": "event_whenflagclicked", "next": "setx" },
+            "setx": {
+                "opcode": "motion_setx",
+                "inputs": { "X": [1, [4, "5"]] },
+            },
+        }));
+        vm.green_flag();
+        assert_eq!(vm.pending_threads().len(), 3);
+
+        assert!(vm.step().expect("step failed"));
+        let threads = vm.pending_threads();
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads.iter().filter(|thread| thread.waiting).count(), 1);
+        assert!(threads.iter().all(|thread| thread.sprite == "Sprite"));
+        assert_eq!(vm.finished_threads(), 1);
+    }
+
+    #[test]
+    fn mod_takes_the_sign_of_the_divisor() {
+        assert_eq!(binary_op("operator_mod", 17.0, 5.0), "2");
+        assert_eq!(binary_op("operator_mod", -17.0, 5.0), "3");
+        // Floored modulo, so 17 = -4 * -5 + -3.
+        assert_eq!(binary_op("operator_mod", 17.0, -5.0), "-3");
+        assert_eq!(binary_op("operator_mod", -17.0, -5.0), "-2");
+        assert_eq!(binary_op("operator_mod", 17.0, 0.0), "NaN");
+    }
+
+    #[test]
+    fn round_rounds_halves_up() {
+        let round = |n| eval(&call("operator_round", [("NUM", num(n))]));
+        assert_eq!(round(2.5), "3");
+        assert_eq!(round(-2.5), "-2");
+        assert_eq!(round(2.4), "2");
+        assert_eq!(round(-2.6), "-3");
+        assert_eq!(round(-0.4), "0");
+    }
+
+    #[test]
+    fn step_until_idle_runs_to_completion() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "repeat" },
+            "repeat": {
+                "opcode": "control_repeat",
+                "inputs": {
+                    "TIMES": [1, [6, "3"]],
+                    "SUBSTACK": [2, "move"],
+                },
+                "next": "wait",
+            },
+            "move": {
+                "opcode": "motion_changexby",
+                "inputs": { "DX": [1, [4, "10"]] },
+            },
+            "wait": {
+                "opcode": "control_wait",
+                "inputs": { "DURATION": [1, [5, "1"]] },
+            },
+        }));
+        vm.green_flag();
+        vm.step_until_idle(10).expect("project didn't finish");
+        assert_eq!(vm.position_of("Sprite"), Some((30.0, 0.0)));
+        assert!(vm.pending_threads().is_empty());
+    }
+
+    #[test]
+    fn step_until_idle_gives_up_on_endless_loops() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "loop" },
+            "loop": {
+                "opcode": "control_forever",
+                "inputs": { "SUBSTACK": [2, "move"] },
+            },
+            "move": {
+                "opcode": "motion_changexby",
+                "inputs": { "DX": [1, [4, "1"]] },
+            },
+        }));
+        vm.green_flag();
+        assert!(matches!(vm.step_until_idle(5), Err(VMError::StepLimit(5))));
+        assert!(vm.pending_threads().is_empty());
+    }
+
+    /// A project whose green flag script calls a custom block that moves the
+    /// sprite in a loop, which runs without screen refresh if `warp` is set.
+    fn warp_project(warp: &str) -> VM {
+        project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "call" },
+            "call": {
+                "opcode": "procedures_call",
+                "mutation": {
+                    "proccode": "walk",
+                    "argumentids": "[]",
+                },
+            },
+            "definition": {
+                "opcode": "procedures_definition",
+                "inputs": { "custom_block": [1, "prototype"] },
+                "next": "repeat",
+            },
+            "prototype": {
+                "opcode": "procedures_prototype",
+                "mutation": {
+                    "proccode": "walk",
+                    "argumentids": "[]",
+                    "argumentnames": "[]",
+                    "warp": warp,
+                },
+            },
+            "repeat": {
+                "opcode": "control_repeat",
+                "inputs": {
+                    "TIMES": [1, [6, "10"]],
+                    "SUBSTACK": [2, "move"],
+                },
+            },
+            "move": {
+                "opcode": "motion_changexby",
+                "inputs": { "DX": [1, [4, "1"]] },
+            },
+        }))
+    }
+
+    #[test]
+    fn warp_procedures_finish_loops_in_one_step() {
+        let vm = warp_project("true");
+        vm.green_flag();
+        vm.step_until_idle(1).expect("warp loop yielded");
+        assert_eq!(vm.position_of("Sprite"), Some((10.0, 0.0)));
+
+        let vm = warp_project("false");
+        vm.green_flag();
+        assert!(vm.step().expect("step failed"));
+        assert_eq!(vm.position_of("Sprite"), Some((1.0, 0.0)));
+        vm.step_until_idle(20).expect("loop didn't finish");
+        assert_eq!(vm.position_of("Sprite"), Some((10.0, 0.0)));
+    }
+
+    #[test]
+    fn clone_scripts_run_alongside_their_creator() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "clone" },
+            "clone": {
+                "opcode": "control_create_clone_of",
+                "inputs": { "CLONE_OPTION": [1, "menu"] },
+                "next": "repeat",
+            },
+            "menu": {
+                "opcode": "control_create_clone_of_menu",
+                "fields": { "CLONE_OPTION": ["_myself_", null] },
+            },
+            "repeat": {
+                "opcode": "control_repeat",
+                "inputs": {
+                    "TIMES": [1, [6, "2"]],
+                    "SUBSTACK": [2, "move"],
+                },
+                "next": "look",
+            },
+            "move": {
+                "opcode": "motion_changeyby",
+                "inputs": { "DY": [1, [4, "1"]] },
+            },
+            "look": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["seen", "seen"] },
+                "inputs": { "VALUE": [3, [12, "count", "count"], [10, ""]] },
+            },
+            "hat": { "opcode": "control_start_as_clone", "next": "count" },
+            "count": {
+                "opcode": "control_repeat",
+                "inputs": {
+                    "TIMES": [1, [6, "5"]],
+                    "SUBSTACK": [2, "increment"],
+                },
+            },
+            "increment": {
+                "opcode": "data_changevariableby",
+                "fields": { "VARIABLE": ["count", "count"] },
+                "inputs": { "VALUE": [1, [4, "1"]] },
+            },
+        }));
+        vm.green_flag();
+        vm.step_until_idle(10).expect("project didn't finish");
+        let vars = vm.snapshot().vars;
+        // The creator looks at the count while the clone is still counting.
+        assert_eq!(vars.get("seen").map(Value::to_num), Some(1.0));
+        assert_eq!(vars.get("count").map(Value::to_num), Some(5.0));
+    }
+
+    #[test]
+    fn key_presses_start_key_hats_on_the_next_step() {
+        let vm = project(json!({
+            "space": {
+                "opcode": "event_whenkeypressed",
+                "fields": { "KEY_OPTION": ["space", null] },
+                "next": "right",
+            },
+            "right": {
+                "opcode": "motion_changexby",
+                "inputs": { "DX": [1, [4, "10"]] },
+            },
+            "any": {
+                "opcode": "event_whenkeypressed",
+                "fields": { "KEY_OPTION": ["any", null] },
+                "next": "up",
+            },
+            "up": {
+                "opcode": "motion_changeyby",
+                "inputs": { "DY": [1, [4, "1"]] },
+            },
+            "a": {
+                "opcode": "event_whenkeypressed",
+                "fields": { "KEY_OPTION": ["a", null] },
+                "next": "left",
+            },
+            "left": {
+                "opcode": "motion_changexby",
+                "inputs": { "DX": [1, [4, "-100"]] },
+            },
+        }));
+        vm.push_event(Event::KeyPressed("space".to_owned()));
+        assert_eq!(vm.position_of("Sprite"), Some((0.0, 0.0)));
+        vm.step().expect("step failed");
+        assert_eq!(vm.position_of("Sprite"), Some((10.0, 1.0)));
+    }
+
+    #[test]
+    fn directions_wrap_into_the_half_open_range() {
+        let vm = project(json!({}));
+        let sprite = &vm.sprites["Sprite"];
+        set_direction(sprite, 180.0);
+        assert_eq!(vm.direction_of("Sprite"), Some(180.0));
+        set_direction(sprite, 181.0);
+        assert_eq!(vm.direction_of("Sprite"), Some(-179.0));
+        set_direction(sprite, -180.0);
+        assert_eq!(vm.direction_of("Sprite"), Some(180.0));
+        set_direction(sprite, 450.0);
+        assert_eq!(vm.direction_of("Sprite"), Some(90.0));
+    }
+
+    #[test]
+    fn costume_names_take_precedence_over_numbers() {
+        let vm = project_with_sprite(json!({
+            "name": "Sprite",
+            "blocks": {},
+            "costumes": [{ "name": "2" }, { "name": "a" }, { "name": "b" }],
+        }));
+        let sprite = &vm.sprites["Sprite"];
+        let switch = |costume: Value| {
+            switch_costume(sprite, &costume);
+            vm.costume_of("Sprite")
+        };
+        assert_eq!(switch(Value::String("2".into())), Some("2"));
+        assert_eq!(switch(Value::Num(2.0)), Some("a"));
+        assert_eq!(switch(Value::String("3".into())), Some("b"));
+        assert_eq!(switch(Value::String("a".into())), Some("a"));
+        assert_eq!(switch(Value::Num(4.0)), Some("2"));
+        assert_eq!(switch(Value::String("next costume".into())), Some("a"));
+        assert_eq!(switch(Value::String("missing".into())), Some("a"));
+    }
+
+    #[test]
+    fn conditional_loops_check_before_the_first_iteration() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "until" },
+            "until": {
+                "opcode": "control_repeat_until",
+                "inputs": {
+                    "CONDITION": [2, "true"],
+                    "SUBSTACK": [2, "right"],
+                },
+                "next": "while",
+            },
+            "true": {
+                "opcode": "operator_equals",
+                "inputs": {
+                    "OPERAND1": [1, [10, "1"]],
+                    "OPERAND2": [1, [10, "1"]],
+                },
+            },
+            "right": {
+                "opcode": "motion_changexby",
+                "inputs": { "DX": [1, [4, "1"]] },
+            },
+            "while": {
+                "opcode": "control_while",
+                "inputs": {
+                    "CONDITION": [2, "false"],
+                    "SUBSTACK": [2, "up"],
+                },
+            },
+            "false": {
+                "opcode": "operator_equals",
+                "inputs": {
+                    "OPERAND1": [1, [10, "1"]],
+                    "OPERAND2": [1, [10, "2"]],
+                },
+            },
+            "up": {
+                "opcode": "motion_changeyby",
+                "inputs": { "DY": [1, [4, "1"]] },
+            },
+        }));
+        vm.green_flag();
+        vm.step_until_idle(1).expect("a loop body ran");
+        assert_eq!(vm.position_of("Sprite"), Some((0.0, 0.0)));
+    }
+
+    #[test]
+    fn lists_can_be_used_after_deleting_everything() {
+        let list = json!(["items", "items"]);
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "add_a" },
+            "add_a": {
+                "opcode": "data_addtolist",
+                "fields": { "LIST": list },
+                "inputs": { "ITEM": [1, [10, "a"]] },
+                "next": "add_b",
+            },
+            "add_b": {
+                "opcode": "data_addtolist",
+                "fields": { "LIST": list },
+                "inputs": { "ITEM": [1, [10, "b"]] },
+                "next": "clear",
+            },
+            "clear": {
+                "opcode": "data_deletealloflist",
+                "fields": { "LIST": list },
+                "next": "measure",
+            },
+            "measure": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["length", "length"] },
+                "inputs": { "VALUE": [3, "length_of", [10, ""]] },
+                "next": "add_c",
+            },
+            "length_of": {
+                "opcode": "data_lengthoflist",
+                "fields": { "LIST": list },
+            },
+            "add_c": {
+                "opcode": "data_addtolist",
+                "fields": { "LIST": list },
+                "inputs": { "ITEM": [1, [10, "c"]] },
+            },
+        }));
+        vm.green_flag();
+        vm.step_until_idle(1).expect("project didn't finish");
+        let state = vm.snapshot();
+        assert_eq!(state.vars["length"].to_string(), "0");
+        let items = state.lists["items"].iter().map(Value::to_string);
+        assert_eq!(items.collect::<Vec<_>>(), ["c"]);
+    }
+
+    #[test]
+    fn number_arguments_stay_numbers() {
+        let arg = json!({
+            "opcode": "argument_reporter_string_number",
+            "fields": { "VALUE": ["n", null] },
+        });
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "call" },
+            "call": {
+                "opcode": "procedures_call",
+                "inputs": { "arg": [1, [4, "5"]] },
+                "mutation": {
+                    "proccode": "check %s",
+                    "argumentids": "[\"arg\"]",
+                },
+            },
+            "definition": {
+                "opcode": "procedures_definition",
+                "inputs": { "custom_block": [1, "prototype"] },
+                "next": "keep",
+            },
+            "prototype": {
+                "opcode": "procedures_prototype",
+                "mutation": {
+                    "proccode": "check %s",
+                    "argumentids": "[\"arg\"]",
+                    "argumentnames": "[\"n\"]",
+                },
+            },
+            "keep": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["arg", "arg"] },
+                "inputs": { "VALUE": [3, "kept_arg", [10, ""]] },
+                "next": "if",
+            },
+            "kept_arg": arg,
+            "if": {
+                "opcode": "control_if",
+                "inputs": {
+                    "CONDITION": [2, "equals"],
+                    "SUBSTACK": [2, "matched"],
+                },
+            },
+            "equals": {
+                "opcode": "operator_equals",
+                "inputs": {
+                    "OPERAND1": [3, "compared_arg", [10, ""]],
+                    "OPERAND2": [1, [10, "5.0"]],
+                },
+            },
+            "compared_arg": arg,
+            "matched": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["matched", "matched"] },
+                "inputs": { "VALUE": [1, [10, "yes"]] },
+            },
+        }));
+        vm.green_flag();
+        vm.step_until_idle(1).expect("project didn't finish");
+        let vars = vm.snapshot().vars;
+        assert!(matches!(vars["arg"], Value::Num(_)));
+        assert_eq!(
+            vars.get("matched").map(Value::to_string).as_deref(),
+            Some("yes")
+        );
+    }
+
+    #[test]
+    fn equality_has_no_tolerance() {
+        let sum =
+            call("operator_add", [("NUM1", num(0.1)), ("NUM2", num(0.2))]);
+        let equals = call(
+            "operator_equals",
+            [("OPERAND1", sum), ("OPERAND2", num(0.3))],
+        );
+        assert_eq!(eval(&equals), "false");
+    }
+
+    #[test]
+    fn dividing_by_zero_gives_infinity_or_nan() {
+        assert_eq!(binary_op("operator_divide", 5.0, 0.0), "Infinity");
+        assert_eq!(binary_op("operator_divide", -5.0, 0.0), "-Infinity");
+        assert_eq!(binary_op("operator_divide", 0.0, 0.0), "NaN");
+        assert_eq!(binary_op("operator_mod", 5.0, 0.0), "NaN");
+    }
+
+    #[test]
+    fn letter_of_uses_the_string_form_of_numbers() {
+        let letter = call(
+            "operator_letter_of",
+            [("LETTER", num(2.0)), ("STRING", num(15.0))],
+        );
+        assert_eq!(eval(&letter), "5");
+    }
+
+    /// A microphone that counts how often its loudness is read.
+    #[derive(Debug)]
+    struct CountingMicrophone(Rc<Cell<u32>>);
+
+    impl InputSource for CountingMicrophone {
+        fn key_pressed(&self, _key: &str) -> bool {
+            false
+        }
+
+        fn mouse_pos(&self) -> (f64, f64) {
+            (0.0, 0.0)
+        }
+
+        fn mouse_down(&self) -> bool {
+            false
+        }
+
+        fn loudness(&self) -> f64 {
+            self.0.set(self.0.get() + 1);
+            0.5
+        }
+    }
+
+    #[test]
+    fn wait_durations_are_evaluated_once() {
+        let wait = |next: Option<&str>| {
+            json!({
+                "opcode": "control_wait",
+                "inputs": { "DURATION": [3, "loudness", [5, "1"]] },
+                "next": next,
+            })
+        };
+        let mut vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "first" },
+            "first": wait(Some("second")),
+            "second": wait(None),
+            "loudness": { "opcode": "sensing_loudness" },
+        }));
+        let reads = Rc::new(Cell::new(0));
+        vm.set_input_source(Box::new(CountingMicrophone(Rc::clone(&reads))));
+        vm.green_flag();
+        vm.step_until_idle(10).expect("project didn't finish");
+        assert_eq!(reads.get(), 2);
+        assert!(vm.clock().now() >= 1.0);
+    }
+
+    #[test]
+    fn compressed_reporters_are_skipped() {
+        let vm = project(json!({
+            "loose": [12, "score", "score-id", 10, 20],
+            "flag": { "opcode": "event_whenflagclicked", "next": "set" },
+            "set": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["x", "x-id"] },
+                "inputs": { "VALUE": [1, [10, "done"]] },
+            },
+        }));
+        vm.run().expect("running failed");
+        assert_eq!(vm.snapshot().vars["x-id"].to_string(), "done");
+    }
+
+    #[test]
+    fn stop_other_scripts_keeps_running_the_current_one() {
+        let vm = project(json!({
+            "looper": { "opcode": "event_whenflagclicked", "next": "loop" },
+            "loop": {
+                "opcode": "control_forever",
+                "inputs": { "SUBSTACK": [2, "count"] },
+            },
+            "count": {
+                "opcode": "data_changevariableby",
+                "fields": { "VARIABLE": ["n", "n"] },
+                "inputs": { "VALUE": [1, [4, "1"]] },
+            },
+            "stopper": { "opcode": "event_whenflagclicked", "next": "repeat" },
+            // The loop yields a few times so that the other script gets to
+            // run before it is stopped.
+            "repeat": {
+                "opcode": "control_repeat",
+                "inputs": { "TIMES": [1, [6, "3"]] },
+                "next": "stop",
+            },
+            "stop": {
+                "opcode": "control_stop",
+                "fields": { "STOP_OPTION": ["other scripts in sprite", null] },
+                "mutation": { "hasnext": "true" },
+                "next": "done",
+            },
+            "done": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["done", "done"] },
+                "inputs": { "VALUE": [1, [10, "yes"]] },
+            },
+        }));
+        vm.run().expect("running failed");
+        let vars = vm.snapshot().vars;
+        assert_eq!(vars["done"].to_string(), "yes");
+        assert!(vars["n"].to_num() > 0.0);
+    }
+
+    #[test]
+    fn broadcasts_from_the_host_only_start_their_receivers() {
+        let vm = project(json!({
+            "looper": { "opcode": "event_whenflagclicked", "next": "loop" },
+            "loop": {
+                "opcode": "control_forever",
+                "inputs": { "SUBSTACK": [2, "count"] },
+            },
+            "count": {
+                "opcode": "data_changevariableby",
+                "fields": { "VARIABLE": ["n", "n"] },
+                "inputs": { "VALUE": [1, [4, "1"]] },
+            },
+            "receiver": {
+                "opcode": "event_whenbroadcastreceived",
+                "fields": { "BROADCAST_OPTION": ["go", "go-id"] },
+                "next": "set",
+            },
+            "set": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["got", "got"] },
+                "inputs": { "VALUE": [1, [10, "go"]] },
+            },
+        }));
+        vm.green_flag();
+        vm.step().expect("stepping failed");
+        vm.broadcast("go");
+        vm.step().expect("stepping failed");
+        vm.step().expect("stepping failed");
+
+        let vars = vm.snapshot().vars;
+        assert_eq!(vars["got"].to_string(), "go");
+        // The forever loop is still running rather than having been stopped
+        // or run to completion by the broadcast.
+        assert_eq!(vm.pending_threads().len(), 1);
+    }
+
+    #[test]
+    fn waits_yield_even_if_they_are_already_over() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "wait" },
+            "wait": {
+                "opcode": "control_wait",
+                "inputs": { "DURATION": [1, [5, "0"]] },
+                "next": "set",
+            },
+            "set": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["x", "x"] },
+                "inputs": { "VALUE": [1, [10, "done"]] },
+            },
+        }));
+        vm.green_flag();
+        assert!(vm.step().expect("stepping failed"));
+        assert!(!vm.snapshot().vars.contains_key("x"));
+        assert!(!vm.step().expect("stepping failed"));
+        assert_eq!(vm.snapshot().vars["x"].to_string(), "done");
+    }
+
+    #[test]
+    fn concurrent_asks_are_answered_in_turn() {
+        let ask = |question: &str, next: &str| {
+            json!({
+                "opcode": "sensing_askandwait",
+                "inputs": { "QUESTION": [1, [10, question]] },
+                "next": next,
+            })
+        };
+        let store = |var: &str| {
+            json!({
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": [var, var] },
+                "inputs": { "VALUE": [3, "answer", [10, ""]] },
+            })
+        };
+        let mut vm = project(json!({
+            "flag_a": { "opcode": "event_whenflagclicked", "next": "ask_a" },
+            "ask_a": ask("a?", "store_a"),
+            "store_a": store("a"),
+            "flag_b": { "opcode": "event_whenflagclicked", "next": "ask_b" },
+            "ask_b": ask("b?", "store_b"),
+            "store_b": store("b"),
+            "answer": { "opcode": "sensing_answer" },
+        }));
+        let output = Capture::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.set_answers(Box::new(&b"first\nsecond\n"[..]));
+        vm.set_echo_answers(true);
+        vm.run().expect("running failed");
+
+        // Either script may ask first, but the second question isn't shown
+        // until the first one has been answered.
+        let vars = vm.snapshot().vars;
+        let (first, second) = match &*output.contents() {
+            "a?first\nb?second\n" => ("a", "b"),
+            "b?first\na?second\n" => ("b", "a"),
+            output => panic!("asks were interleaved: {output:?}"),
+        };
+        assert_eq!(vars[first].to_string(), "first");
+        assert_eq!(vars[second].to_string(), "second");
+    }
+
+    #[test]
+    fn numeric_indices_count_from_one() {
+        let vm = project(json!({}));
+        let resolve = |index| vm.resolve_index(&Value::Num(index), 3);
+        assert_eq!(resolve(1.0), IndexResolution::Nth(0));
+        assert_eq!(resolve(3.9), IndexResolution::Nth(2));
+        assert_eq!(resolve(0.0), IndexResolution::Invalid);
+        assert_eq!(resolve(4.0), IndexResolution::Invalid);
+        assert_eq!(resolve(f64::NAN), IndexResolution::Invalid);
+    }
+
+    #[test]
+    fn named_indices_resolve_like_in_scratch() {
+        let vm = project(json!({})).with_rng_seed(0);
+        let resolve = |index: &str, len| {
+            vm.resolve_index(&Value::String(index.into()), len)
+        };
+        assert_eq!(resolve("last", 3), IndexResolution::Nth(2));
+        assert_eq!(resolve("last", 0), IndexResolution::Invalid);
+        assert_eq!(resolve("all", 3), IndexResolution::All);
+        assert_eq!(resolve("random", 0), IndexResolution::Invalid);
+        for _ in 0..20 {
+            let IndexResolution::Nth(i) = resolve("random", 3) else {
+                panic!("random index didn't pick an item");
+            };
+            assert!(i < 3);
+        }
+        assert!(matches!(resolve("any", 1), IndexResolution::Nth(0)));
+    }
+
+    #[test]
+    fn list_blocks_and_letter_of_use_the_same_indices() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "add_a" },
+            "add_a": {
+                "opcode": "data_addtolist",
+                "fields": { "LIST": ["l", "l"] },
+                "inputs": { "ITEM": [1, [10, "a"]] },
+                "next": "insert_b",
+            },
+            "insert_b": {
+                "opcode": "data_insertatlist",
+                "fields": { "LIST": ["l", "l"] },
+                "inputs": {
+                    "INDEX": [1, [7, "last"]],
+                    "ITEM": [1, [10, "b"]],
+                },
+                "next": "replace_first",
+            },
+            "replace_first": {
+                "opcode": "data_replaceitemoflist",
+                "fields": { "LIST": ["l", "l"] },
+                "inputs": {
+                    "INDEX": [1, [7, "1"]],
+                    "ITEM": [1, [10, "c"]],
+                },
+            },
+        }));
+        vm.run().expect("running failed");
+        let items = |vm: &VM| {
+            vm.snapshot().lists["l"]
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(items(&vm), ["c", "b"]);
+
+        let letter = |letter: &str| {
+            eval(&call(
+                "operator_letter_of",
+                [
+                    ("STRING", Expr::Lit(Value::String("abc".into()))),
+                    ("LETTER", Expr::Lit(Value::String(letter.into()))),
+                ],
+            ))
+        };
+        assert_eq!(letter("last"), "c");
+        assert_eq!(letter("2"), "b");
+        assert_eq!(letter("4"), "");
+    }
+
+    #[test]
+    fn sizes_are_clamped() {
+        let set_size = |size: &str| {
+            let vm = project(json!({
+                "flag": { "opcode": "event_whenflagclicked", "next": "size" },
+                "size": {
+                    "opcode": "looks_setsizeto",
+                    "inputs": { "SIZE": [1, [4, size]] },
+                },
+            }));
+            vm.run().expect("running failed");
+            vm.size_of("Sprite")
+        };
+        assert_eq!(set_size("1e6"), Some(MAX_SIZE));
+        assert_eq!(set_size("-20"), Some(MIN_SIZE));
+        assert_eq!(set_size("150"), Some(150.0));
+    }
+
+    #[test]
+    fn inserted_numbers_stay_numbers() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "insert" },
+            "insert": {
+                "opcode": "data_insertatlist",
+                "fields": { "LIST": ["l", "l"] },
+                "inputs": {
+                    "INDEX": [1, [7, "1"]],
+                    "ITEM": [3, "sum", [10, ""]],
+                },
+                "next": "find",
+            },
+            "sum": {
+                "opcode": "operator_add",
+                "inputs": {
+                    "NUM1": [1, [4, "2"]],
+                    "NUM2": [1, [4, "3"]],
+                },
+            },
+            "find": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["found", "found"] },
+                "inputs": { "VALUE": [3, "item_num", [10, ""]] },
+            },
+            "item_num": {
+                "opcode": "data_itemnumoflist",
+                "fields": { "LIST": ["l", "l"] },
+                "inputs": { "ITEM": [1, [4, "5"]] },
+            },
+        }));
+        vm.run().expect("running failed");
+        let snapshot = vm.snapshot();
+        let [item @ Value::Num(_)] = &snapshot.lists["l"][..] else {
+            panic!("the item isn't a number");
+        };
+        assert_eq!(item.to_string(), "5");
+        assert_eq!(snapshot.vars["found"].to_string(), "1");
+    }
+
+    /// A project whose green flag script calls a custom block that sets
+    /// `last` to its argument and then calls itself with the argument plus
+    /// one, until the argument reaches `limit`.
+    fn counting_project(limit: &str) -> VM {
+        let arg = json!({
+            "opcode": "argument_reporter_string_number",
+            "fields": { "VALUE": ["n", null] },
+        });
+        let mutation = json!({
+            "proccode": "count %s",
+            "argumentids": "[\"arg\"]",
+            "argumentnames": "[\"n\"]",
+        });
+        project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "start" },
+            "start": {
+                "opcode": "procedures_call",
+                "inputs": { "arg": [1, [4, "1"]] },
+                "mutation": mutation,
+            },
+            "definition": {
+                "opcode": "procedures_definition",
+                "inputs": { "custom_block": [1, "prototype"] },
+                "next": "set",
+            },
+            "prototype": {
+                "opcode": "procedures_prototype",
+                "mutation": mutation,
+            },
+            "set": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["last", "last"] },
+                "inputs": { "VALUE": [3, "set_arg", [10, ""]] },
+                "next": "if",
+            },
+            "set_arg": arg,
+            "if": {
+                "opcode": "control_if",
+                "inputs": {
+                    "CONDITION": [2, "less"],
+                    "SUBSTACK": [2, "recurse"],
+                },
+            },
+            "less": {
+                "opcode": "operator_lt",
+                "inputs": {
+                    "OPERAND1": [3, "less_arg", [10, ""]],
+                    "OPERAND2": [1, [10, limit]],
+                },
+            },
+            "less_arg": arg,
+            "recurse": {
+                "opcode": "procedures_call",
+                "inputs": { "arg": [3, "plus", [4, ""]] },
+                "mutation": mutation,
+            },
+            "plus": {
+                "opcode": "operator_add",
+                "inputs": {
+                    "NUM1": [3, "plus_arg", [4, ""]],
+                    "NUM2": [1, [4, "1"]],
+                },
+            },
+            "plus_arg": arg,
+        }))
+    }
+
+    #[test]
+    fn procedures_can_pass_expressions_of_their_arguments_to_themselves() {
+        let vm = counting_project("10");
+        vm.run().expect("running failed");
+        assert_eq!(vm.snapshot().vars["last"].to_string(), "10");
+    }
+
+    #[test]
+    fn calls_with_undefined_arguments_are_errors() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "call" },
+            "call": {
+                "opcode": "procedures_call",
+                "inputs": { "stray": [1, [4, "1"]] },
+                "mutation": {
+                    "proccode": "f %s",
+                    "argumentids": "[\"stray\"]",
+                },
+            },
+            "definition": {
+                "opcode": "procedures_definition",
+                "inputs": { "custom_block": [1, "prototype"] },
+                "next": "move",
+            },
+            "prototype": {
+                "opcode": "procedures_prototype",
+                "mutation": {
+                    "proccode": "f %s",
+                    "argumentids": "[\"arg\"]",
+                    "argumentnames": "[\"n\"]",
+                },
+            },
+            "move": {
+                "opcode": "motion_changexby",
+                "inputs": { "DX": [1, [4, "1"]] },
+            },
+        }));
+        let res = vm.run();
+        assert!(
+            matches!(
+                &res,
+                Err(VMError::UnknownArgument { id, .. }) if id == "stray"
+            ),
+            "{res:?}"
+        );
+    }
+
+    #[test]
+    fn deep_recursion_does_not_overflow_the_stack() {
+        let vm = counting_project("5000");
+        vm.run().expect("running failed");
+        assert_eq!(vm.snapshot().vars["last"].to_string(), "5000");
+    }
+
+    #[test]
+    fn procedures_that_stop_themselves_leave_no_arguments_behind() {
+        let arg = |name: &str| {
+            json!({
+                "opcode": "argument_reporter_string_number",
+                "fields": { "VALUE": [name, null] },
+            })
+        };
+        let set = |var: &str, value: &str, next: Option<&str>| {
+            json!({
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": [var, var] },
+                "inputs": { "VALUE": [3, value, [10, ""]] },
+                "next": next,
+            })
+        };
+        let call = |n: &str, next: &str| {
+            json!({
+                "opcode": "procedures_call",
+                "inputs": { "arg": [1, [4, n]] },
+                "mutation": {
+                    "proccode": "f %s",
+                    "argumentids": "[\"arg\"]",
+                },
+                "next": next,
+            })
+        };
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "first" },
+            "first": call("1", "second"),
+            "second": call("2", "after"),
+            "after": set("after", "after_arg", None),
+            "after_arg": arg("n"),
+            "definition": {
+                "opcode": "procedures_definition",
+                "inputs": { "custom_block": [1, "prototype"] },
+                "next": "set",
+            },
+            "prototype": {
+                "opcode": "procedures_prototype",
+                "mutation": {
+                    "proccode": "f %s",
+                    "argumentids": "[\"arg\"]",
+                    "argumentnames": "[\"n\"]",
+                },
+            },
+            "set": set("seen", "seen_arg", Some("stop")),
+            "seen_arg": arg("n"),
+            "stop": {
+                "opcode": "control_stop",
+                "fields": { "STOP_OPTION": ["this script", null] },
+                "next": "unreachable",
+            },
+            "unreachable": set("seen", "unreachable_arg", None),
+            "unreachable_arg": arg("missing"),
+        }));
+        vm.run().expect("running failed");
+        let vars = vm.snapshot().vars;
+        // The second call sees its own argument, and once both calls have
+        // stopped, no argument is left over for the script that made them.
+        assert_eq!(vars["seen"].to_string(), "2");
+        assert_eq!(vars["after"].to_string(), "");
+    }
+
+    #[test]
+    fn rounding_mathops_handle_negative_numbers() {
+        let mathop = |op: &str, n: f64| {
+            eval(&Expr::MathOp {
+                op: op.into(),
+                num: Box::new(num(n)),
+            })
+        };
+        assert_eq!(mathop("floor", -0.5), "-1");
+        assert_eq!(mathop("ceiling", -0.0), "0");
+        assert_eq!(mathop("ceiling", -0.5), "0");
+    }
+
+    #[test]
+    fn strict_mode_rejects_unimplemented_blocks() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "rest" },
+            "rest": { "opcode": "music_restForBeats" },
+        }));
+        let res = vm.check_opcodes();
+        assert!(
+            matches!(
+                &res,
+                Err(DeError::UnsupportedOpcode(opcode))
+                    if opcode == "music_restForBeats"
+            ),
+            "{res:?}"
+        );
+
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "move" },
+            "move": {
+                "opcode": "motion_changexby",
+                "inputs": { "DX": [1, [4, "1"]] },
+            },
+        }));
+        assert!(vm.check_opcodes().is_ok());
+    }
+
+    #[test]
+    fn list_contents_only_leave_out_spaces_between_single_letters() {
+        let vm = project_with_sprite(json!({
+            "name": "Sprite",
+            "blocks": {},
+            "lists": {
+                "letters": ["letters", ["a", "b", "c"]],
+                "words": ["words", ["a", "bc", "d"]],
+                "digits": ["digits", ["1", "2"]],
+                "numbers": ["numbers", [1, 2]],
+            },
+        }));
+        let contents = |list_id: &str| {
+            vm.eval_reporter(
+                "Sprite",
+                &Expr::ListContents {
+                    list_id: list_id.into(),
+                },
+            )
+            .expect("evaluation failed")
+            .to_string()
+        };
+        assert_eq!(contents("letters"), "abc");
+        assert_eq!(contents("words"), "a bc d");
+        assert_eq!(contents("digits"), "12");
+        assert_eq!(contents("numbers"), "1 2");
+    }
+
+    #[test]
+    fn booleans_compare_like_their_string_and_number_forms() {
+        let compare = |opcode: &str, lhs: Value, rhs: Value| {
+            eval(&call(
+                opcode,
+                [("OPERAND1", Expr::Lit(lhs)), ("OPERAND2", Expr::Lit(rhs))],
+            ))
+        };
+        let true_str = Value::String("true".into());
+        assert_eq!(
+            compare("operator_equals", Value::Bool(true), true_str),
+            "true"
+        );
+        assert_eq!(
+            compare("operator_lt", Value::Bool(false), Value::Num(1.0)),
+            "true"
+        );
+        assert_eq!(
+            compare("operator_gt", Value::Bool(true), Value::Num(0.0)),
+            "true"
+        );
+    }
+
+    #[test]
+    fn empty_forever_loops_are_counted() {
+        let vm = project(json!({
+            "empty": { "opcode": "event_whenflagclicked", "next": "idle" },
+            "idle": { "opcode": "control_forever", "inputs": {} },
+            "busy": { "opcode": "event_whenflagclicked", "next": "loop" },
+            "loop": {
+                "opcode": "control_forever",
+                "inputs": { "SUBSTACK": [2, "move"] },
+            },
+            "move": {
+                "opcode": "motion_changexby",
+                "inputs": { "DX": [1, [4, "1"]] },
+            },
+        }));
+        assert_eq!(vm.count_empty_forever_loops(), 1);
+    }
+
+    /// Input that holds down the space key with the mouse at a fixed
+    /// position and a fixed loudness.
+    #[derive(Debug)]
+    struct MockInput;
+
+    impl InputSource for MockInput {
+        fn key_pressed(&self, key: &str) -> bool {
+            matches!(key, "space" | "any")
+        }
+
+        fn mouse_pos(&self) -> (f64, f64) {
+            (-120.0, 45.0)
+        }
+
+        fn mouse_down(&self) -> bool {
+            true
+        }
+
+        fn loudness(&self) -> f64 {
+            42.0
+        }
+    }
+
+    #[test]
+    fn sensing_blocks_read_the_input_source() {
+        let mut vm = project(json!({}));
+        vm.set_input_source(Box::new(MockInput));
+        let sense = |expr: &Expr| {
+            vm.eval_reporter("Sprite", expr)
+                .expect("evaluation failed")
+                .to_string()
+        };
+        let key_pressed = |key: &str| {
+            sense(&call(
+                "sensing_keypressed",
+                [("KEY_OPTION", Expr::Lit(Value::String(key.into())))],
+            ))
+        };
+        assert_eq!(key_pressed("space"), "true");
+        assert_eq!(key_pressed("a"), "false");
+        assert_eq!(sense(&call("sensing_mousex", [])), "-120");
+        assert_eq!(sense(&call("sensing_loudness", [])), "42");
+    }
+
+    #[test]
+    fn the_tempo_comes_from_the_stage() {
+        let project = json!({
+            "targets": [
+                {
+                    "name": "Stage",
+                    "isStage": true,
+                    "blocks": {},
+                    "tempo": 120,
+                },
+                { "name": "Sprite", "blocks": {} },
+            ]
+        });
+        let vm = serde_json::from_str::<VM>(&project.to_string())
+            .expect("invalid test project");
+        let tempo = vm
+            .eval_reporter("Sprite", &call("music_getTempo", []))
+            .expect("evaluation failed");
+        assert_eq!(tempo.to_string(), "120");
+    }
+
+    #[test]
+    fn runaway_joins_hit_the_string_length_limit() {
+        let mut vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "loop" },
+            "loop": {
+                "opcode": "control_forever",
+                "inputs": { "SUBSTACK": [2, "set"] },
+            },
+            "set": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["s", "s"] },
+                "inputs": { "VALUE": [3, "join", [10, ""]] },
+            },
+            "join": {
+                "opcode": "operator_join",
+                "inputs": {
+                    "STRING1": [3, [12, "s", "s"], [10, ""]],
+                    "STRING2": [1, [10, "abc"]],
+                },
+            },
+        }));
+        vm.set_max_string_len(Some(100));
+        assert!(matches!(vm.run(), Err(VMError::StringTooLong(100))));
+    }
+
+    #[test]
+    fn tight_loops_time_out() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "loop" },
+            "loop": {
+                "opcode": "control_forever",
+                "inputs": { "SUBSTACK": [2, "change"] },
+            },
+            "change": {
+                "opcode": "data_changevariableby",
+                "fields": { "VARIABLE": ["n", "n"] },
+                "inputs": { "VALUE": [1, [4, "1"]] },
+            },
+        }));
+        let res = vm.run_with_timeout(time::Duration::from_millis(50));
+        assert!(matches!(res, Err(VMError::Timeout)));
+    }
+
+    #[test]
+    fn unsupported_opcodes_are_listed() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "rest" },
+            "rest": { "opcode": "music_restForBeats", "next": "say" },
+            "say": {
+                "opcode": "looks_say",
+                "inputs": { "MESSAGE": [3, "mathop", [10, ""]] },
+            },
+            "mathop": {
+                "opcode": "operator_mathop",
+                "fields": { "OPERATOR": ["len", null] },
+                "inputs": { "NUM": [3, "loudness", [4, "0"]] },
+            },
+            "loudness": { "opcode": "sensing_frobnicate" },
+        }));
+        let unsupported = vm.unsupported_opcodes();
+        assert_eq!(
+            unsupported.into_iter().collect::<Vec<_>>(),
+            [
+                "music_restForBeats",
+                "operator_mathop len",
+                "sensing_frobnicate",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_mathops_fail_when_evaluated() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "say" },
+            "say": {
+                "opcode": "looks_say",
+                "inputs": { "MESSAGE": [3, "mathop", [10, ""]] },
+            },
+            "mathop": {
+                "opcode": "operator_mathop",
+                "fields": { "OPERATOR": ["len", null] },
+                "inputs": { "NUM": [1, [4, "2"]] },
+            },
+        }));
+        let res = vm.run();
+        assert!(matches!(&res, Err(VMError::UnknownMathOp(op)) if op == "len"));
+    }
+
+    #[test]
+    fn inverse_trigonometry_mathops_report_degrees() {
+        let mathop = |op: &str, n: f64| {
+            eval(&Expr::MathOp {
+                op: op.into(),
+                num: Box::new(num(n)),
+            })
+        };
+        assert_eq!(mathop("asin", 1.0), "90");
+        assert_eq!(mathop("acos", 0.0), "90");
+        assert_eq!(mathop("atan", 1.0), "45");
+    }
+
+    #[test]
+    fn stop_handles_stop_forever_loops_from_other_threads() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "loop" },
+            "loop": {
+                "opcode": "control_forever",
+                "inputs": { "SUBSTACK": [2, "change"] },
+            },
+            "change": {
+                "opcode": "data_changevariableby",
+                "fields": { "VARIABLE": ["n", "n"] },
+                "inputs": { "VALUE": [1, [4, "1"]] },
+            },
+        }));
+        let handle = vm.stop_handle();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(time::Duration::from_millis(20));
+            handle.request_stop();
+        });
+        vm.run().expect("running failed");
+        stopper.join().expect("stopping thread panicked");
+        let n = vm.snapshot().vars["n"].to_num();
+        assert!(n > 0.0, "the loop never ran");
+    }
+
+    #[test]
+    fn snapshots_diff_changed_variables_and_lists() {
+        let vm = project_with_sprite(json!({
+            "name": "Sprite",
+            "variables": { "a-id": ["a", "old"], "b-id": ["b", "same"] },
+            "lists": { "l-id": ["l", []], "m-id": ["m", ["same"]] },
+            "blocks": {
+                "flag": { "opcode": "event_whenflagclicked", "next": "set" },
+                "set": {
+                    "opcode": "data_setvariableto",
+                    "fields": { "VARIABLE": ["a", "a-id"] },
+                    "inputs": { "VALUE": [1, [10, "new"]] },
+                    "next": "add",
+                },
+                "add": {
+                    "opcode": "data_addtolist",
+                    "fields": { "LIST": ["l", "l-id"] },
+                    "inputs": { "ITEM": [1, [10, "item"]] },
+                    "next": "same",
+                },
+                "same": {
+                    "opcode": "data_setvariableto",
+                    "fields": { "VARIABLE": ["b", "b-id"] },
+                    "inputs": { "VALUE": [1, [10, "same"]] },
+                },
+            },
+        }));
+        let before = vm.snapshot();
+        vm.run().expect("running failed");
+        let after = vm.snapshot();
+        assert_eq!(before.diff(&after), ["a-id", "l-id"]);
+        assert!(after.diff(&after).is_empty());
+    }
+
+    #[test]
+    fn fencing_keeps_sprites_on_the_stage() {
+        let blocks = json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "go" },
+            "go": {
+                "opcode": "motion_gotoxy",
+                "inputs": { "X": [1, [4, "1000"]], "Y": [1, [4, "-1000"]] },
+            },
+        });
+        let mut vm = project(blocks.clone());
+        vm.set_fencing(true);
+        vm.run().expect("running failed");
+        assert_eq!(vm.position_of("Sprite"), Some((240.0, -180.0)));
+
+        let vm = project(blocks);
+        vm.run().expect("running failed");
+        assert_eq!(vm.position_of("Sprite"), Some((1000.0, -1000.0)));
+    }
+
+    #[test]
+    fn attributes_of_sprites_include_their_variables() {
+        let vm = project_with_sprite(json!({
+            "name": "Sprite1",
+            "variables": { "score-id": ["score", 42] },
+            "blocks": {},
+        }));
+        let attribute = |property: &str| {
+            vm.eval_reporter(
+                "Sprite1",
+                &Expr::AttributeOf {
+                    property: property.into(),
+                    object: Box::new(Expr::Lit(Value::String(
+                        "Sprite1".into(),
+                    ))),
+                },
+            )
+            .expect("evaluation failed")
+            .to_string()
+        };
+        assert_eq!(attribute("score"), "42");
+        assert_eq!(attribute("lives"), "0");
+    }
+
+    #[test]
+    fn virtual_clocks_resolve_waits_instantly() {
+        let mut vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "wait" },
+            "wait": {
+                "opcode": "control_wait",
+                "inputs": { "DURATION": [1, [4, "1000"]] },
+                "next": "set",
+            },
+            "set": {
+                "opcode": "data_setvariableto",
+                "fields": { "VARIABLE": ["t", "t"] },
+                "inputs": { "VALUE": [3, "timer", [10, ""]] },
+            },
+            "timer": { "opcode": "sensing_timer" },
+        }));
+        vm.set_clock(Box::<VirtualClock>::default());
+        let start = time::Instant::now();
+        vm.run().expect("running failed");
+        assert!(start.elapsed() < time::Duration::from_secs(1));
+        assert!(vm.snapshot().vars["t"].to_num() >= 1000.0);
+    }
+
+    #[test]
+    fn broadcasts_run_receivers_in_every_sprite() {
+        let receiver = |name: &str| {
+            json!({
+                "name": name,
+                "blocks": {
+                    "hat": {
+                        "opcode": "event_whenbroadcastreceived",
+                        "fields": { "BROADCAST_OPTION": ["go", "go-id"] },
+                        "next": "add",
+                        "topLevel": true,
+                    },
+                    "add": {
+                        "opcode": "data_addtolist",
+                        "fields": { "LIST": ["ran", "ran-id"] },
+                        "inputs": { "ITEM": [1, [10, name]] },
+                    },
+                },
+            })
+        };
+        let mut sender = receiver("Sender");
+        sender["blocks"]["flag"] = json!({
+            "opcode": "event_whenflagclicked",
+            "next": "broadcast",
+        });
+        sender["blocks"]["broadcast"] = json!({
+            "opcode": "event_broadcast",
+            "inputs": { "BROADCAST_INPUT": [1, [11, "go", "go-id"]] },
+        });
+        let vm = project_with_sprites(vec![sender, receiver("Receiver")]);
+        vm.run().expect("running failed");
+        let mut ran = vm.snapshot().lists["ran-id"]
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        ran.sort();
+        assert_eq!(ran, ["Receiver", "Sender"]);
+    }
+
+    #[test]
+    fn headless_runs_collect_output() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "call" },
+            "call": {
+                "opcode": "procedures_call",
+                "inputs": { "arg": [1, [10, "hello world"]] },
+                "mutation": {
+                    "proccode": "print %s",
+                    "argumentids": "[\"arg\"]",
+                },
+            },
+            "definition": {
+                "opcode": "procedures_definition",
+                "inputs": { "custom_block": [1, "prototype"] },
+                "next": "hide",
+            },
+            "prototype": {
+                "opcode": "procedures_prototype",
+                "mutation": {
+                    "proccode": "print %s",
+                    "argumentids": "[\"arg\"]",
+                    "argumentnames": "[\"s\"]",
+                },
+            },
+            // Definitions without a body are left out when loading.
+            "hide": { "opcode": "looks_hide" },
+        }));
+        let report = vm.run_headless_collect();
+        assert!(report.error.is_none());
+        assert_eq!(report.output, "hello world");
+    }
+
+    #[test]
+    fn nested_loops_render_as_pseudocode() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "outer" },
+            "outer": {
+                "opcode": "control_repeat",
+                "inputs": {
+                    "TIMES": [1, [6, "10"]],
+                    "SUBSTACK": [2, "inner"],
+                },
+            },
+            "inner": {
+                "opcode": "control_forever",
+                "inputs": { "SUBSTACK": [2, "change"] },
+            },
+            "change": {
+                "opcode": "data_changevariableby",
+                "fields": { "VARIABLE": ["n", "n-id"] },
+                "inputs": { "VALUE": [1, [4, "1"]] },
+            },
+        }));
+        let expected = "\
+// Sprite
+when flag clicked {
+    repeat 10 {
+        forever {
+            change [n-id] by 1
+        }
+    }
+}
+
+// Stage
+
+";
+        assert_eq!(vm.dump_ir(), expected);
+    }
+
+    #[test]
+    fn echoed_answers_complete_the_transcript() {
+        let transcript = |echo_answers: bool| {
+            let mut vm = project(json!({
+                "flag": { "opcode": "event_whenflagclicked", "next": "ask" },
+                "ask": {
+                    "opcode": "sensing_askandwait",
+                    "inputs": { "QUESTION": [1, [10, "name? "]] },
+                },
+            }));
+            let output = Capture::default();
+            vm.set_output(Box::new(output.clone()));
+            vm.set_answers(Box::new(&b"Scratch Cat\n"[..]));
+            vm.set_echo_answers(echo_answers);
+            vm.run().expect("running failed");
+            output.contents()
+        };
+        assert_eq!(transcript(true), "name? Scratch Cat\n");
+        assert_eq!(transcript(false), "name? ");
+    }
+
+    #[test]
+    fn motion_blocks_on_the_stage_do_nothing() {
+        let stage = json!({
+            "name": "Stage",
+            "isStage": true,
+            "blocks": {
+                "flag": { "opcode": "event_whenflagclicked", "next": "go" },
+                "go": {
+                    "opcode": "motion_gotoxy",
+                    "inputs": { "X": [1, [4, "10"]], "Y": [1, [4, "20"]] },
+                    "next": "change",
+                },
+                "change": {
+                    "opcode": "motion_changexby",
+                    "inputs": { "DX": [1, [4, "5"]] },
+                },
+            },
+        });
+        let project = json!({ "targets": [stage] });
+        let vm = serde_json::from_str::<VM>(&project.to_string())
+            .expect("invalid test project");
+        vm.run().expect("running failed");
+        assert_eq!(vm.position_of("Stage"), Some((0.0, 0.0)));
+    }
+
+    #[test]
+    fn position_reporters_round_fractional_positions() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "change_x" },
+            "change_x": {
+                "opcode": "motion_changexby",
+                "inputs": { "DX": [1, [4, "2.6"]] },
+                "next": "change_y",
+            },
+            "change_y": {
+                "opcode": "motion_changeyby",
+                "inputs": { "DY": [1, [4, "-1.2"]] },
+            },
+        }));
+        vm.run().expect("running failed");
+        let reporter = |opcode: &str| {
+            vm.eval_reporter("Sprite", &call(opcode, []))
+                .expect("evaluation failed")
+                .to_string()
+        };
+        assert_eq!(reporter("motion_xposition"), "3");
+        assert_eq!(reporter("motion_yposition"), "-1");
+        assert_eq!(vm.position_of("Sprite"), Some((2.6, -1.2)));
+    }
+
+    #[test]
+    fn step_until_idle_gives_up_on_waits_that_never_end() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "wait" },
+            "wait": {
+                "opcode": "control_wait_until",
+                "inputs": { "CONDITION": [2, "never"] },
+            },
+            "never": {
+                "opcode": "operator_gt",
+                "inputs": {
+                    "OPERAND1": [1, [10, "1"]],
+                    "OPERAND2": [1, [10, "2"]],
+                },
+            },
+        }));
+        vm.green_flag();
+        assert!(matches!(vm.step_until_idle(5), Err(VMError::StepLimit(5))));
+        assert!(vm.pending_threads().is_empty());
+    }
+
+    #[test]
+    fn accessors_report_the_state_of_sprites() {
+        let vm = project_with_sprite(json!({
+            "name": "Sprite",
+            "costumes": [{ "name": "a" }, { "name": "b" }],
+            "blocks": {
+                "flag": { "opcode": "event_whenflagclicked", "next": "go" },
+                "go": {
+                    "opcode": "motion_gotoxy",
+                    "inputs": { "X": [1, [4, "12"]], "Y": [1, [4, "-34"]] },
+                    "next": "switch",
+                },
+                "switch": {
+                    "opcode": "looks_switchcostumeto",
+                    "inputs": { "COSTUME": [1, "menu"] },
+                },
+                "menu": {
+                    "opcode": "looks_costume",
+                    "fields": { "COSTUME": ["b", null] },
+                },
+            },
+        }));
+        assert_eq!(vm.costume_of("Sprite"), Some("a"));
+        assert_eq!(vm.costume_number_of("Sprite"), Some(1));
+        vm.run().expect("running failed");
+        assert_eq!(vm.position_of("Sprite"), Some((12.0, -34.0)));
+        assert_eq!(vm.costume_of("Sprite"), Some("b"));
+        assert_eq!(vm.costume_number_of("Sprite"), Some(2));
+        assert_eq!(vm.costume_number_of("Nobody"), None);
+    }
+
+    #[test]
+    fn lists_start_with_their_saved_items() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/prepopulated_list");
+        let mut vm = VM::from_project_dir(&dir).expect("invalid fixture");
+        vm.set_output(Box::new(std::io::sink()));
+        vm.run().expect("running failed");
+        let scores = vm.list_snapshot("scores").expect("missing list");
+        let scores = scores.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(scores, ["3", "1", "2", "4"]);
+        assert_eq!(vm.snapshot().vars["count"].to_string(), "3");
+    }
+
+    #[test]
+    fn turning_by_non_numbers_keeps_the_direction() {
+        let vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "right" },
+            "right": {
+                "opcode": "motion_turnright",
+                "inputs": { "DEGREES": [1, [10, "banana"]] },
+                "next": "left",
+            },
+            "left": {
+                "opcode": "motion_turnleft",
+                "inputs": { "DEGREES": [1, [10, "NaN"]] },
+            },
+        }));
+        vm.run().expect("running failed");
+        assert_eq!(vm.direction_of("Sprite"), Some(90.0));
+    }
+
+    #[test]
+    fn scripted_answers_and_captured_output() {
+        let answer = json!({ "opcode": "sensing_answer" });
+        let mut vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "ask" },
+            "ask": {
+                "opcode": "sensing_askandwait",
+                "inputs": { "QUESTION": [1, [10, "colour? "]] },
+                "next": "say",
+            },
+            "say": {
+                "opcode": "looks_say",
+                "inputs": { "MESSAGE": [3, "answer", [10, ""]] },
+                "next": "think",
+            },
+            "think": {
+                "opcode": "looks_think",
+                "inputs": { "MESSAGE": [3, "answer_again", [10, ""]] },
+            },
+            "answer": answer,
+            "answer_again": answer,
+        }));
+        let output = Capture::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.set_answers(Box::new(&b"blue\n"[..]));
+        vm.run().expect("running failed");
+        assert_eq!(output.contents(), "colour? blue\n(blue)\n");
+    }
+
+    #[test]
+    fn list_snapshots_copy_the_items() {
+        let vm = project_with_sprite(json!({
+            "name": "Sprite",
+            "lists": { "l-id": ["l", []] },
+            "blocks": {
+                "flag": { "opcode": "event_whenflagclicked", "next": "a" },
+                "a": {
+                    "opcode": "data_addtolist",
+                    "fields": { "LIST": ["l", "l-id"] },
+                    "inputs": { "ITEM": [1, [10, "a"]] },
+                    "next": "b",
+                },
+                "b": {
+                    "opcode": "data_addtolist",
+                    "fields": { "LIST": ["l", "l-id"] },
+                    "inputs": { "ITEM": [1, [4, "2"]] },
+                },
+            },
+        }));
+        assert_eq!(vm.list_snapshot("l").map(|l| l.len()), Some(0));
+        vm.run().expect("running failed");
+        let items = vm.list_snapshot("l").expect("missing list");
+        let items = items.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(items, ["a", "2"]);
+        assert!(vm.list_snapshot("missing").is_none());
+    }
+
+    #[test]
+    fn stopping_mid_glide_leaves_sprites_part_way() {
+        let mut vm = project(json!({
+            "flag": { "opcode": "event_whenflagclicked", "next": "glide" },
+            "glide": {
+                "opcode": "motion_glidesecstoxy",
+                "inputs": {
+                    "SECS": [1, [4, "10"]],
+                    "X": [1, [4, "100"]],
+                    "Y": [1, [4, "0"]],
+                },
+            },
+        }));
+        // A virtual clock would finish the glide at once.
+        vm.set_clock(Box::<SystemClock>::default());
+        let handle = vm.stop_handle();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(time::Duration::from_millis(200));
+            handle.request_stop();
+        });
+        let start = time::Instant::now();
+        vm.run().expect("running failed");
+        stopper.join().expect("stopping thread panicked");
+        assert!(start.elapsed() < time::Duration::from_secs(5));
+        let (x, _) = vm.position_of("Sprite").expect("missing sprite");
+        assert!(0.0 < x && x < 100.0, "glided to {x}");
+    }
+
+    #[test]
+    fn variables_and_lists_can_be_dropped_into_inputs() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/reporter_inputs");
+        let vm = VM::from_project_dir(&dir).expect("invalid fixture");
+        vm.run().expect("running failed");
+        let vars = vm.snapshot().vars;
+        assert_eq!(vars["sum"].to_string(), "7");
+        assert_eq!(vars["letters_joined"].to_string(), "xyz");
+    }
+
+    #[test]
+    fn seeded_runs_are_reproducible() {
+        let run = |seed: u64| {
+            let vm = project(json!({
+                "flag": { "opcode": "event_whenflagclicked", "next": "loop" },
+                "loop": {
+                    "opcode": "control_repeat",
+                    "inputs": {
+                        "TIMES": [1, [6, "10"]],
+                        "SUBSTACK": [2, "add"],
+                    },
+                    "next": "pick",
+                },
+                "add": {
+                    "opcode": "data_addtolist",
+                    "fields": { "LIST": ["rolls", "rolls"] },
+                    "inputs": { "ITEM": [3, "random", [10, ""]] },
+                },
+                "random": {
+                    "opcode": "operator_random",
+                    "inputs": {
+                        "FROM": [1, [4, "1"]],
+                        "TO": [1, [4, "1000"]],
+                    },
+                },
+                "pick": {
+                    "opcode": "data_setvariableto",
+                    "fields": { "VARIABLE": ["picked", "picked"] },
+                    "inputs": { "VALUE": [3, "item", [10, ""]] },
+                },
+                "item": {
+                    "opcode": "data_itemoflist",
+                    "fields": { "LIST": ["rolls", "rolls"] },
+                    "inputs": { "INDEX": [1, [7, "random"]] },
+                },
+            }))
+            .with_rng_seed(seed);
+            vm.run().expect("running failed");
+            vm.snapshot()
+        };
+        assert!(run(1).diff(&run(1)).is_empty());
+        assert_eq!(run(1).diff(&run(2)), ["picked", "rolls"]);
+    }
+
+    #[test]
+    fn duplicate_target_names_are_errors() {
+        let sprite = json!({ "name": "Sprite", "blocks": {} });
+        let project = json!({ "targets": [sprite, sprite] });
+        let err = serde_json::from_str::<VM>(&project.to_string())
+            .expect_err("loading should have failed");
+        let expected = DeError::DuplicateTarget("Sprite".to_owned());
+        assert!(err.to_string().starts_with(&expected.to_string()));
+    }
+}
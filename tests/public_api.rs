@@ -0,0 +1,34 @@
+use std::path::Path;
+use unsb3::prelude::*;
+
+fn fixture(name: &str) -> VM {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    VM::from_project_dir(&dir).expect("invalid fixture")
+}
+
+#[test]
+fn projects_can_be_loaded_and_run() {
+    let vm = fixture("prepopulated_list");
+    let report = vm.run_headless_collect();
+    assert!(report.error.is_none());
+
+    let scores = vm.list_snapshot("scores").expect("missing list");
+    let expected = [Value::num(3.0), Value::num(1.0), Value::num(2.0)]
+        .into_iter()
+        .chain([Value::str("4")]);
+    assert_eq!(scores.len(), 4);
+    for (score, expected) in scores.iter().zip(expected) {
+        assert_eq!(score.to_string(), expected.to_string());
+    }
+}
+
+#[test]
+fn missing_projects_are_load_errors() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/missing");
+    assert!(matches!(
+        VM::from_project_dir(&dir),
+        Err(LoadError::IOError(_))
+    ));
+}